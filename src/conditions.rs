@@ -0,0 +1,126 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// Replicas are being added, removed, rolled or migrated between pools.
+pub const PROGRESSING: &str = "Progressing";
+/// At least the desired number of replicas are ready.
+pub const AVAILABLE: &str = "Available";
+/// A replica is being retired (lock revoked, ScaleDown running or waiting for it to exit).
+pub const SCALING_DOWN: &str = "ScalingDown";
+/// The last ScaleUp or ScaleDown hook failed.
+pub const HOOK_FAILED: &str = "HookFailed";
+/// Replicas which should be ready are not.
+pub const DEGRADED: &str = "Degraded";
+
+/// Condition mirrors the standard kubernetes condition shape so that generic tooling
+/// (e.g. `kubectl wait --for=condition=Available`) understands it.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// One of True, False, Unknown.
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: Option<Time>,
+}
+
+impl Condition {
+    pub fn new(type_: &str, status: bool, reason: &str, message: impl Into<String>) -> Self {
+        Condition {
+            type_: String::from(type_),
+            status: String::from(if status { "True" } else { "False" }),
+            reason: Some(String::from(reason)),
+            message: Some(message.into()),
+            last_transition_time: None,
+        }
+    }
+}
+
+/// Sets `new` in `conditions`, replacing any existing condition of the same type.
+/// The transition time only moves when the status changes.
+pub fn set_condition(conditions: &mut Vec<Condition>, mut new: Condition) {
+    match conditions.iter_mut().find(|c| c.type_ == new.type_) {
+        Some(existing) => {
+            new.last_transition_time = if existing.status == new.status {
+                existing.last_transition_time.clone()
+            } else {
+                Some(Time(Utc::now()))
+            };
+            *existing = new;
+        }
+        None => {
+            new.last_transition_time = Some(Time(Utc::now()));
+            conditions.push(new);
+        }
+    }
+}
+
+pub fn find<'a>(conditions: &'a [Condition], type_: &str) -> Option<&'a Condition> {
+    conditions.iter().find(|c| c.type_ == type_)
+}
+
+pub fn is_true(conditions: &[Condition], type_: &str) -> bool {
+    find(conditions, type_)
+        .map(|c| c.status == "True")
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::chrono::TimeZone;
+
+    #[test]
+    fn sets_conditions() {
+        let then = Some(Time(Utc.timestamp_opt(0, 0).unwrap()));
+        let existing = |status| Condition {
+            last_transition_time: then.clone(),
+            ..Condition::new(AVAILABLE, status, "Old", "old")
+        };
+
+        // New conditions are appended with a transition time.
+        let mut conditions = vec![existing(true)];
+        set_condition(
+            &mut conditions,
+            Condition::new(DEGRADED, false, "Ready", "all ready"),
+        );
+        assert_eq!(conditions.len(), 2);
+        assert!(conditions[1].last_transition_time.is_some());
+        assert_ne!(conditions[1].last_transition_time, then);
+
+        // Keeping the status replaces reason & message but keeps the transition time.
+        set_condition(
+            &mut conditions,
+            Condition::new(AVAILABLE, true, "New", "new"),
+        );
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(conditions[0].reason.as_deref(), Some("New"));
+        assert_eq!(conditions[0].message.as_deref(), Some("new"));
+        assert_eq!(conditions[0].last_transition_time, then);
+
+        // Changing it moves the transition time.
+        set_condition(
+            &mut conditions,
+            Condition::new(AVAILABLE, false, "New", "new"),
+        );
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(conditions[0].status, "False");
+        assert!(conditions[0].last_transition_time.is_some());
+        assert_ne!(conditions[0].last_transition_time, then);
+        assert!(!is_true(&conditions, AVAILABLE));
+
+        // A transition time set by the caller is ignored.
+        let mut conditions = vec![existing(true)];
+        set_condition(
+            &mut conditions,
+            Condition {
+                last_transition_time: Some(Time(Utc.timestamp_opt(1, 0).unwrap())),
+                ..Condition::new(AVAILABLE, true, "New", "new")
+            },
+        );
+        assert_eq!(conditions[0].last_transition_time, then);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::errors::*;
+use crate::gs::GratefulSet;
+use crate::gsp::GratefulSetPool;
+use crate::schema;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceColumnDefinition, CustomResourceDefinition, CustomResourceDefinitionNames,
+    CustomResourceDefinitionSpec, CustomResourceDefinitionVersion, CustomResourceSubresourceScale,
+    CustomResourceSubresourceStatus, CustomResourceSubresources, CustomResourceValidation,
+    JSONSchemaProps,
+};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1;
+use kube::api::{Meta, PatchParams};
+use kube::{Api, Client};
+use log::info;
+
+/// The CustomResourceDefinitions for every kind managed by the operator.
+pub fn crds() -> Result<Vec<CustomResourceDefinition>> {
+    Ok(vec![
+        to_v1(GratefulSet::crd(), schema::gratefulset()?)?,
+        to_v1(GratefulSetPool::crd(), schema::gratefulsetpool()?)?,
+    ])
+}
+
+// Converts a derived v1beta1 crd into its apiextensions.k8s.io/v1 form, attaching the schema.
+// The derive is kept on v1beta1 because its v1 output drops the subresources & printer
+// columns, which v1 expects per version rather than crd wide.
+fn to_v1(
+    crd: v1beta1::CustomResourceDefinition,
+    schema: JSONSchemaProps,
+) -> Result<CustomResourceDefinition> {
+    let name = Meta::name(&crd);
+    let spec = crd.spec;
+    let subresources = spec.subresources.map(|x| CustomResourceSubresources {
+        scale: x.scale.map(|x| CustomResourceSubresourceScale {
+            label_selector_path: x.label_selector_path,
+            spec_replicas_path: x.spec_replicas_path,
+            status_replicas_path: x.status_replicas_path,
+        }),
+        status: x.status.map(|x| CustomResourceSubresourceStatus(x.0)),
+    });
+    let columns = spec.additional_printer_columns.map(|cols| {
+        cols.into_iter()
+            .map(|x| CustomResourceColumnDefinition {
+                description: x.description,
+                format: x.format,
+                json_path: x.json_path,
+                name: x.name,
+                priority: x.priority,
+                type_: x.type_,
+            })
+            .collect::<Vec<_>>()
+    });
+    let versions = spec
+        .versions
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| format!("crd {} has no versions", name))?
+        .into_iter()
+        .map(|x| CustomResourceDefinitionVersion {
+            additional_printer_columns: columns.clone(),
+            name: x.name,
+            schema: Some(CustomResourceValidation {
+                open_api_v3_schema: Some(schema.clone()),
+            }),
+            served: x.served,
+            storage: x.storage,
+            subresources: subresources.clone(),
+        })
+        .collect();
+    Ok(CustomResourceDefinition {
+        metadata: crd.metadata,
+        spec: CustomResourceDefinitionSpec {
+            conversion: None,
+            group: spec.group,
+            names: CustomResourceDefinitionNames {
+                categories: spec.names.categories,
+                kind: spec.names.kind,
+                list_kind: spec.names.list_kind,
+                plural: spec.names.plural,
+                short_names: spec.names.short_names,
+                singular: spec.names.singular,
+            },
+            preserve_unknown_fields: None,
+            scope: spec.scope,
+            versions,
+        },
+        status: None,
+    })
+}
+
+/// Renders the CRDs as a multi document yaml manifest, suitable for `kubectl apply -f -`.
+pub fn manifests() -> Result<String> {
+    let docs = crds()?
+        .iter()
+        .map(serde_yaml::to_string)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(docs.join("\n"))
+}
+
+/// Installs (or updates) the CRDs via server-side apply, so it's safe to run repeatedly.
+pub async fn install(client: Client) -> Result<()> {
+    let api: Api<CustomResourceDefinition> = Api::all(client);
+    for crd in crds()? {
+        let name = Meta::name(&crd);
+        api.patch(
+            &name,
+            &PatchParams::apply("gratefulset-mgr").force(),
+            serde_json::to_vec(&crd)?,
+        )
+        .await
+        .chain_err(|| format!("installing crd {}", name))?;
+        info!("installed crd {}", name);
+    }
+    Ok(())
+}
+
+/// Ensures the CRDs have been installed.
+pub async fn check(client: Client) -> Result<()> {
+    let api: Api<CustomResourceDefinition> = Api::all(client);
+    for crd in crds()? {
+        let name = Meta::name(&crd);
+        api.get(&name).await.chain_err(|| {
+            format!(
+                "crd {} not found, install it via `gratefulset install-crds` first",
+                name
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::gs::GratefulSetStatus;
+    use serde_json::Value;
+
+    // Whether the `.a.b` path used by a subresource or printer column names a serialized field.
+    fn resolves(x: &Value, path: &str) -> bool {
+        path.trim_start_matches('.')
+            .split('.')
+            .try_fold(x, |x, key| x.get(key))
+            .is_some()
+    }
+
+    #[test]
+    fn scale_and_column_paths_resolve() {
+        // The pool's statusReplicasPath once pointed at `stsStatus` while the status was still
+        // serialized as `sts_status`, leaving `kubectl scale` without a status count.
+        let mut gs = fixtures::gratefulset(|_| {});
+        gs.status = Some(GratefulSetStatus::default());
+        let gsp = fixtures::found_pool(&gs, 3, 0);
+        let objects = vec![
+            serde_json::to_value(gs).unwrap(),
+            serde_json::to_value(gsp).unwrap(),
+        ];
+
+        for (crd, object) in crds().unwrap().into_iter().zip(objects) {
+            let crd = serde_json::to_value(crd).unwrap();
+            for version in crd["spec"]["versions"].as_array().unwrap() {
+                let scale = &version["subresources"]["scale"];
+                let columns = version["additionalPrinterColumns"].as_array();
+                let paths = vec![&scale["specReplicasPath"], &scale["statusReplicasPath"]]
+                    .into_iter()
+                    .chain(columns.into_iter().flatten().map(|c| &c["jsonPath"]));
+                for path in paths {
+                    let path = path.as_str().unwrap();
+                    assert!(
+                        resolves(&object, path),
+                        "{}: {}",
+                        crd["metadata"]["name"],
+                        path
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use k8s_openapi::api::apps::v1::{RollingUpdateStatefulSetStrategy, StatefulSetSpec};
+use k8s_openapi::api::core::v1::{
+    Container, EmptyDirVolumeSource, PersistentVolumeClaim, PodSpec, Probe, Volume,
+};
+
+// Mirrors the defaulting the API server applies to a StatefulSetSpec (apps/v1 & the embedded
+// core/v1 pod template & claim templates). A spec read back from the API server has had these
+// filled in, so both sides of a comparison are normalized before comparing them; otherwise an
+// unset `imagePullPolicy` or `terminationMessagePath` looks like a change forever.
+//
+// Only defaults which don't depend on cluster configuration are covered. Admission plugins
+// (e.g. injected sidecars or default storage classes) & quantity canonicalization ("1000m" vs "1")
+// still show up as differences.
+
+/// Returns `spec` with the API server's defaults filled in.
+pub fn sts_spec(spec: &StatefulSetSpec) -> StatefulSetSpec {
+    let mut x = spec.clone();
+    x.replicas.get_or_insert(1);
+    x.pod_management_policy
+        .get_or_insert_with(|| String::from("OrderedReady"));
+    x.revision_history_limit.get_or_insert(10);
+
+    let strategy = x.update_strategy.get_or_insert_with(Default::default);
+    let type_ = strategy
+        .type_
+        .get_or_insert_with(|| String::from("RollingUpdate"));
+    if type_ == "RollingUpdate" {
+        strategy
+            .rolling_update
+            .get_or_insert_with(RollingUpdateStatefulSetStrategy::default)
+            .partition
+            .get_or_insert(0);
+    }
+
+    if let Some(spec) = x.template.spec.as_mut() {
+        pod_spec(spec);
+    }
+    if let Some(claims) = x.volume_claim_templates.as_mut() {
+        claims.iter_mut().for_each(claim);
+    }
+    x
+}
+
+fn pod_spec(spec: &mut PodSpec) {
+    spec.dns_policy
+        .get_or_insert_with(|| String::from("ClusterFirst"));
+    spec.restart_policy
+        .get_or_insert_with(|| String::from("Always"));
+    spec.scheduler_name
+        .get_or_insert_with(|| String::from("default-scheduler"));
+    spec.termination_grace_period_seconds.get_or_insert(30);
+    spec.security_context.get_or_insert_with(Default::default);
+    spec.enable_service_links.get_or_insert(true);
+    // serviceAccount is the deprecated alias the API server mirrors serviceAccountName into.
+    if spec.service_account.is_none() {
+        spec.service_account = spec.service_account_name.clone();
+    }
+
+    spec.containers.iter_mut().for_each(container);
+    if let Some(inits) = spec.init_containers.as_mut() {
+        inits.iter_mut().for_each(container);
+    }
+    if let Some(vols) = spec.volumes.as_mut() {
+        vols.iter_mut().for_each(volume);
+    }
+}
+
+fn container(c: &mut Container) {
+    c.termination_message_path
+        .get_or_insert_with(|| String::from("/dev/termination-log"));
+    c.termination_message_policy
+        .get_or_insert_with(|| String::from("File"));
+    c.resources.get_or_insert_with(Default::default);
+    if c.image_pull_policy.is_none() {
+        c.image_pull_policy = Some(String::from(pull_policy(c.image.as_deref())));
+    }
+
+    if let Some(ports) = c.ports.as_mut() {
+        for p in ports {
+            p.protocol.get_or_insert_with(|| String::from("TCP"));
+        }
+    }
+    if let Some(env) = c.env.as_mut() {
+        for e in env {
+            if let Some(field_ref) = e.value_from.as_mut().and_then(|v| v.field_ref.as_mut()) {
+                field_ref
+                    .api_version
+                    .get_or_insert_with(|| String::from("v1"));
+            }
+        }
+    }
+    for p in [
+        &mut c.liveness_probe,
+        &mut c.readiness_probe,
+        &mut c.startup_probe,
+    ]
+    .iter_mut()
+    .filter_map(|p| p.as_mut())
+    {
+        probe(p);
+    }
+}
+
+// Images without a tag or tagged `latest` are always pulled.
+fn pull_policy(image: Option<&str>) -> &'static str {
+    let image = image.unwrap_or_default();
+    // digests pin the image regardless of tag.
+    if image.contains('@') {
+        return "IfNotPresent";
+    }
+    let name = image.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once(':') {
+        Some((_, tag)) if tag != "latest" => "IfNotPresent",
+        _ => "Always",
+    }
+}
+
+fn probe(p: &mut Probe) {
+    p.timeout_seconds.get_or_insert(1);
+    p.period_seconds.get_or_insert(10);
+    p.success_threshold.get_or_insert(1);
+    p.failure_threshold.get_or_insert(3);
+    if let Some(get) = p.http_get.as_mut() {
+        get.scheme.get_or_insert_with(|| String::from("HTTP"));
+    }
+}
+
+fn volume(v: &mut Volume) {
+    // A volume without a source is an emptyDir.
+    let has_source = serde_json::to_value(&*v)
+        .ok()
+        .and_then(|x| x.as_object().map(|o| o.len() > 1))
+        .unwrap_or(true);
+    if !has_source {
+        v.empty_dir = Some(EmptyDirVolumeSource::default());
+    }
+
+    if let Some(x) = v.config_map.as_mut() {
+        x.default_mode.get_or_insert(0o644);
+    }
+    if let Some(x) = v.secret.as_mut() {
+        x.default_mode.get_or_insert(0o644);
+    }
+    if let Some(x) = v.downward_api.as_mut() {
+        x.default_mode.get_or_insert(0o644);
+    }
+    if let Some(x) = v.projected.as_mut() {
+        x.default_mode.get_or_insert(0o644);
+    }
+    if let Some(x) = v.host_path.as_mut() {
+        x.type_.get_or_insert_with(String::new);
+    }
+}
+
+fn claim(pvc: &mut PersistentVolumeClaim) {
+    if let Some(spec) = pvc.spec.as_mut() {
+        spec.volume_mode
+            .get_or_insert_with(|| String::from("Filesystem"));
+    }
+    // The API server fills in a `Pending` status, which isn't part of the template.
+    pvc.status = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::{json, Value};
+
+    // Adds the fields which get defaulted to the fixture.
+    fn undefaulted(x: &mut Value) {
+        let container = &mut x["template"]["spec"]["containers"][0];
+        container["ports"] = json!([{ "name": "http", "containerPort": 3100 }]);
+        container["readinessProbe"] = json!({ "httpGet": { "path": "/ready", "port": 3100 } });
+        container["env"] = json!([{
+            "name": "POD_IP",
+            "valueFrom": { "fieldRef": { "fieldPath": "status.podIP" } },
+        }]);
+        x["template"]["spec"]["volumes"] = json!([
+            { "name": "config", "configMap": { "name": "loki" } },
+            { "name": "scratch" },
+        ]);
+    }
+
+    // The fixture as read back from the API server.
+    fn defaulted(x: &mut Value) {
+        undefaulted(x);
+        x["podManagementPolicy"] = json!("OrderedReady");
+        x["revisionHistoryLimit"] = json!(10);
+        x["updateStrategy"] =
+            json!({ "type": "RollingUpdate", "rollingUpdate": { "partition": 0 } });
+        let pod = &mut x["template"]["spec"];
+        pod["dnsPolicy"] = json!("ClusterFirst");
+        pod["restartPolicy"] = json!("Always");
+        pod["schedulerName"] = json!("default-scheduler");
+        pod["terminationGracePeriodSeconds"] = json!(30);
+        pod["securityContext"] = json!({});
+        pod["enableServiceLinks"] = json!(true);
+        pod["volumes"][0]["configMap"]["defaultMode"] = json!(0o644);
+        pod["volumes"][1]["emptyDir"] = json!({});
+        let container = &mut pod["containers"][0];
+        container["imagePullPolicy"] = json!("IfNotPresent");
+        container["terminationMessagePath"] = json!("/dev/termination-log");
+        container["terminationMessagePolicy"] = json!("File");
+        container["resources"] = json!({});
+        container["ports"][0]["protocol"] = json!("TCP");
+        container["env"][0]["valueFrom"]["fieldRef"]["apiVersion"] = json!("v1");
+        let probe = &mut container["readinessProbe"];
+        probe["httpGet"]["scheme"] = json!("HTTP");
+        probe["timeoutSeconds"] = json!(1);
+        probe["periodSeconds"] = json!(10);
+        probe["successThreshold"] = json!(1);
+        probe["failureThreshold"] = json!(3);
+        let claim = &mut x["volumeClaimTemplates"][0];
+        claim["spec"]["volumeMode"] = json!("Filesystem");
+        claim["status"] = json!({ "phase": "Pending" });
+    }
+
+    #[test]
+    fn defaulted_specs_compare_equal() {
+        let ours = fixtures::sts_spec(undefaulted);
+        let theirs = fixtures::sts_spec(defaulted);
+        assert_ne!(ours, theirs);
+        assert_eq!(sts_spec(&ours), sts_spec(&theirs));
+        // Defaulting is idempotent.
+        assert_eq!(sts_spec(&sts_spec(&ours)), sts_spec(&ours));
+        // Explicitly set values aren't overridden.
+        let ondelete = fixtures::sts_spec(|x| x["updateStrategy"] = json!({ "type": "OnDelete" }));
+        assert_eq!(
+            sts_spec(&ondelete).update_strategy,
+            ondelete.update_strategy
+        );
+    }
+
+    #[test]
+    fn pull_policies() {
+        let cases = vec![
+            (None, "Always"),
+            (Some("loki"), "Always"),
+            (Some("loki:latest"), "Always"),
+            (Some("grafana/loki:2.0.0"), "IfNotPresent"),
+            (Some("registry:5000/loki"), "Always"),
+            (Some("registry:5000/loki:2.0.0"), "IfNotPresent"),
+            (Some("loki:latest@sha256:abc"), "IfNotPresent"),
+        ];
+        for (image, policy) in cases {
+            assert_eq!(pull_policy(image), policy, "{:?}", image);
+        }
+    }
+}
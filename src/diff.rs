@@ -0,0 +1,416 @@
+use crate::defaults;
+use k8s_openapi::api::apps::v1::StatefulSetSpec;
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Top level StatefulSetSpec fields which may be updated in place, as allowed by the API server's
+/// statefulset update validation (as of Kubernetes 1.18).
+/// Changing any other field (save replicas) requires a new statefulset, i.e. a new pool.
+pub const MUTABLE: &[&str] = &["template", "updateStrategy"];
+
+/// The kind of change needed to bring a statefulset spec to the desired one,
+/// from least to most disruptive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    None,
+    /// Only the replicas differ.
+    Replicas,
+    /// Fields which can be updated in place differ, the sts will roll its pods.
+    Template,
+    /// Fields which can't be updated in place differ.
+    Immutable,
+}
+
+/// SpecDiff is the structured difference between a desired & a found StatefulSetSpec.
+///
+/// The comparison is driven by the desired spec: only the fields it sets are compared, so fields
+/// the API server defaults or admission plugins inject into the found spec don't register as
+/// changes. Both specs are defaulted first, and unset & empty values compare equal. Maps of
+/// strings (labels, annotations, resource quantities, etc) are compared as a whole, reporting
+/// the map rather than each entry.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpecDiff {
+    /// (found, desired) replicas, if they differ.
+    pub replicas: Option<(i32, i32)>,
+    /// Paths of the differing fields which can be updated in place.
+    pub mutable: Vec<String>,
+    /// Paths of the differing fields which can't be updated in place.
+    pub immutable: Vec<String>,
+}
+
+impl SpecDiff {
+    pub fn new(desired: &StatefulSetSpec, found: &StatefulSetSpec) -> Self {
+        let (desired, found) = (defaults::sts_spec(desired), defaults::sts_spec(found));
+        let to_json = |x: &StatefulSetSpec| serde_json::to_value(x).unwrap_or_default();
+        let mut paths = vec![];
+        walk(
+            "",
+            &to_json(&StatefulSetSpec {
+                replicas: None,
+                ..desired.clone()
+            }),
+            &to_json(&StatefulSetSpec {
+                replicas: None,
+                ..found.clone()
+            }),
+            &mut paths,
+        );
+
+        let (mutable, immutable) = paths.into_iter().partition(|p: &String| {
+            MUTABLE
+                .iter()
+                .any(|m| p == m || p.starts_with(&format!("{}.", m)))
+        });
+        SpecDiff {
+            replicas: Some((found.replicas.unwrap_or(1), desired.replicas.unwrap_or(1)))
+                .filter(|(f, d)| f != d),
+            mutable,
+            immutable,
+        }
+    }
+
+    /// The most disruptive change in the diff.
+    pub fn change(&self) -> Change {
+        if !self.immutable.is_empty() {
+            Change::Immutable
+        } else if !self.mutable.is_empty() {
+            Change::Template
+        } else if self.replicas.is_some() {
+            Change::Replicas
+        } else {
+            Change::None
+        }
+    }
+
+    /// Whether the specs differ by more than their replicas.
+    pub fn rolls(&self) -> bool {
+        self.change() > Change::Replicas
+    }
+}
+
+impl fmt::Display for SpecDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some((from, to)) = self.replicas {
+            parts.push(format!("replicas {} -> {}", from, to));
+        }
+        if !self.mutable.is_empty() {
+            parts.push(format!("changed {}", self.mutable.join(", ")));
+        }
+        if !self.immutable.is_empty() {
+            parts.push(format!("changed immutable {}", self.immutable.join(", ")));
+        }
+        if parts.is_empty() {
+            write!(f, "no changes")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+/// A segment of a field path: an object key or an array index.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parses a field path in the format SpecDiff reports them, e.g. `template.spec.containers[1].image`.
+/// Keys containing `.` or brackets are quoted, e.g. `template.metadata.annotations["prometheus.io/scrape"]`.
+pub fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let err = |msg: &str| format!("invalid path {:?}: {}", path, msg);
+    let mut segments = vec![];
+    let mut rest = path;
+    loop {
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        if end == 0 {
+            return Err(err("empty field name"));
+        }
+        segments.push(Segment::Key(String::from(&rest[..end])));
+        rest = &rest[end..];
+
+        while let Some(bracketed) = rest.strip_prefix('[') {
+            if let Some(quoted) = bracketed.strip_prefix('"') {
+                let end = quoted.find("\"]").ok_or_else(|| err("unclosed key"))?;
+                if end == 0 {
+                    return Err(err("empty field name"));
+                }
+                segments.push(Segment::Key(String::from(&quoted[..end])));
+                rest = &quoted[end + 2..];
+            } else {
+                let end = bracketed.find(']').ok_or_else(|| err("unclosed index"))?;
+                let i = &bracketed[..end];
+                let i = i.parse().map_err(|_| err(&format!("bad index {:?}", i)))?;
+                segments.push(Segment::Index(i));
+                rest = &bracketed[end + 1..];
+            }
+        }
+
+        match rest.strip_prefix('.') {
+            Some(tail) => rest = tail,
+            None if rest.is_empty() => return Ok(segments),
+            None => return Err(err(&format!("unexpected {:?}", rest))),
+        }
+    }
+}
+
+/// Looks up the value at `path` (see `parse_path`), returning None if it's invalid or unset.
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    parse_path(path)
+        .ok()?
+        .iter()
+        .try_fold(value, |x, segment| match segment {
+            Segment::Key(k) => x.get(k),
+            Segment::Index(i) => x.get(i),
+        })
+}
+
+fn is_string_map(x: &Map<String, Value>) -> bool {
+    x.values().all(Value::is_string)
+}
+
+// Whether `x` is unset: absent, null or empty, which serialize alike.
+fn is_unset(x: &Value) -> bool {
+    match x {
+        Value::Null => true,
+        Value::Object(x) => x.is_empty(),
+        Value::Array(x) => x.is_empty(),
+        _ => false,
+    }
+}
+
+// Collects the paths at which `found` differs from the fields set in `desired`.
+fn walk(path: &str, desired: &Value, found: &Value, out: &mut Vec<String>) {
+    let join = |k: &str| {
+        if k.contains(['.', '[', ']']) {
+            format!("{}[{:?}]", path, k)
+        } else if path.is_empty() {
+            String::from(k)
+        } else {
+            format!("{}.{}", path, k)
+        }
+    };
+    match (desired, found) {
+        (Value::Object(d), Value::Object(f)) if !(is_string_map(d) && is_string_map(f)) => {
+            for (k, dv) in d {
+                walk(&join(k), dv, f.get(k).unwrap_or(&Value::Null), out);
+            }
+        }
+        (Value::Array(d), Value::Array(f)) if d.len() == f.len() => {
+            for (i, (dv, fv)) in d.iter().zip(f).enumerate() {
+                walk(&format!("{}[{}]", path, i), dv, fv, out);
+            }
+        }
+        (d, f) => {
+            if d != f && !is_unset(d) {
+                out.push(String::from(path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn diffs_specs() {
+        let probe = |x: &mut Value| {
+            x["template"]["spec"]["containers"][0]["livenessProbe"] =
+                json!({ "httpGet": { "path": "/ready", "port": 3100 } })
+        };
+        let node_selector = |zone: &'static str| {
+            move |x: &mut Value| x["template"]["spec"]["nodeSelector"] = json!({ "zone": zone })
+        };
+        let storage_class =
+            |x: &mut Value| x["volumeClaimTemplates"][0]["spec"]["storageClassName"] = json!("ssd");
+        let none = |_: &mut Value| {};
+
+        type Edit = Box<dyn Fn(&mut Value)>;
+        // name, desired & found edits of the fixture, the expected change & paths.
+        let cases: Vec<(&str, Edit, Edit, Change, Vec<&str>)> = vec![
+            ("same", Box::new(none), Box::new(none), Change::None, vec![]),
+            (
+                "defaulted",
+                Box::new(none),
+                Box::new(|x| x["template"]["spec"]["restartPolicy"] = json!("Always")),
+                Change::None,
+                vec![],
+            ),
+            (
+                "empty",
+                Box::new(none),
+                Box::new(|x| x["template"]["spec"]["nodeSelector"] = json!({})),
+                Change::None,
+                vec![],
+            ),
+            (
+                "replicas",
+                Box::new(|x| x["replicas"] = json!(5)),
+                Box::new(none),
+                Change::Replicas,
+                vec![],
+            ),
+            (
+                "changed image",
+                Box::new(|x| x["template"]["spec"]["containers"][0]["image"] = json!("loki:2.1.0")),
+                Box::new(none),
+                Change::Template,
+                vec!["template.spec.containers[0].image"],
+            ),
+            (
+                "added probe",
+                Box::new(probe),
+                Box::new(none),
+                Change::Template,
+                vec!["template.spec.containers[0].livenessProbe"],
+            ),
+            (
+                "injected",
+                Box::new(none),
+                Box::new(|x| {
+                    x["template"]["spec"]["priority"] = json!(0);
+                    x["template"]["metadata"]["annotations"] = json!({ "sidecar": "injected" });
+                    x["template"]["spec"]["containers"][0]["resources"] =
+                        json!({ "limits": { "cpu": "1" } });
+                }),
+                Change::None,
+                vec![],
+            ),
+            (
+                "removed probe",
+                Box::new(none),
+                Box::new(probe),
+                Change::None,
+                vec![],
+            ),
+            (
+                "added node selector",
+                Box::new(node_selector("a")),
+                Box::new(none),
+                Change::Template,
+                vec!["template.spec.nodeSelector"],
+            ),
+            (
+                "changed node selector",
+                Box::new(node_selector("a")),
+                Box::new(node_selector("b")),
+                Change::Template,
+                vec!["template.spec.nodeSelector"],
+            ),
+            (
+                "removed node selector",
+                Box::new(none),
+                Box::new(node_selector("a")),
+                Change::None,
+                vec![],
+            ),
+            (
+                "removed label",
+                Box::new(none),
+                Box::new(|x| x["template"]["metadata"]["labels"]["tier"] = json!("write")),
+                Change::Template,
+                vec!["template.metadata.labels"],
+            ),
+            (
+                "changed serviceName",
+                Box::new(|x| x["serviceName"] = json!("ingester-headless")),
+                Box::new(none),
+                Change::Immutable,
+                vec!["serviceName"],
+            ),
+            (
+                "added storage class",
+                Box::new(storage_class),
+                Box::new(none),
+                Change::Immutable,
+                vec!["volumeClaimTemplates[0].spec.storageClassName"],
+            ),
+            (
+                "template & immutable",
+                Box::new(|x| {
+                    x["serviceName"] = json!("ingester-headless");
+                    x["template"]["spec"]["containers"][0]["image"] = json!("loki:2.1.0");
+                }),
+                Box::new(none),
+                Change::Immutable,
+                vec!["serviceName", "template.spec.containers[0].image"],
+            ),
+        ];
+
+        for (name, desired, found, change, paths) in cases {
+            let diff = SpecDiff::new(&fixtures::sts_spec(desired), &fixtures::sts_spec(found));
+            assert_eq!(diff.change(), change, "{}: {}", name, diff);
+            let mut found: Vec<_> = diff.mutable.iter().chain(&diff.immutable).collect();
+            found.sort();
+            assert_eq!(found, paths, "{}", name);
+        }
+    }
+
+    #[test]
+    fn mutable_fields() {
+        // The API server rejects updates to any other field but replicas: listing one here
+        // would have the operator patch a sts in place only to get it rejected.
+        assert_eq!(MUTABLE, ["template", "updateStrategy"]);
+        let diff = SpecDiff::new(
+            &fixtures::sts_spec(|x| x["revisionHistoryLimit"] = json!(3)),
+            &fixtures::sts_spec(|_| {}),
+        );
+        assert_eq!(diff.change(), Change::Immutable);
+    }
+
+    #[test]
+    fn parses_paths() {
+        let key = |k: &str| Segment::Key(String::from(k));
+        let cases = vec![
+            ("serviceName", Ok(vec![key("serviceName")])),
+            (
+                "template.spec.containers[1].image",
+                Ok(vec![
+                    key("template"),
+                    key("spec"),
+                    key("containers"),
+                    Segment::Index(1),
+                    key("image"),
+                ]),
+            ),
+            (
+                "template.metadata.annotations[\"prometheus.io/scrape\"]",
+                Ok(vec![
+                    key("template"),
+                    key("metadata"),
+                    key("annotations"),
+                    key("prometheus.io/scrape"),
+                ]),
+            ),
+            (
+                "a[0][1]",
+                Ok(vec![key("a"), Segment::Index(0), Segment::Index(1)]),
+            ),
+            ("a[\"b.c\"].d", Ok(vec![key("a"), key("b.c"), key("d")])),
+            ("", Err("empty field name")),
+            ("a..b", Err("empty field name")),
+            ("a.", Err("empty field name")),
+            ("[0]", Err("empty field name")),
+            ("a[\"\"]", Err("empty field name")),
+            ("a[0", Err("unclosed index")),
+            ("a[x]", Err("bad index")),
+            ("a[\"b]", Err("unclosed key")),
+            ("a[0]b", Err("unexpected")),
+        ];
+        for (path, want) in cases {
+            match (parse_path(path), want) {
+                (Ok(got), Ok(want)) => assert_eq!(got, want, "{}", path),
+                (Err(got), Err(want)) => assert!(got.contains(want), "{}: {}", path, got),
+                (got, want) => panic!("{}: got {:?}, want {:?}", path, got, want),
+            }
+        }
+
+        let spec = json!({ "template": { "metadata": { "annotations": { "a.b/c": "true" } } } });
+        let path = "template.metadata.annotations[\"a.b/c\"]";
+        assert_eq!(lookup(&spec, path), Some(&json!("true")));
+        assert_eq!(lookup(&spec, "template.metadata.labels"), None);
+    }
+}
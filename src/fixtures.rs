@@ -0,0 +1,69 @@
+// Fixtures shared by the unit tests: a Loki ingester statefulset and builders around it.
+// Each builder takes an `edit` applied to the stsSpec JSON before it's deserialized, so
+// tests only spell out the fields they care about. Fixtures used by a single module live with
+// its tests instead.
+
+use crate::gs::{GratefulSet, GratefulSetSpec};
+use crate::gsp::{GratefulSetPool, GratefulSetPoolStatus};
+use k8s_openapi::api::apps::v1::{StatefulSetSpec, StatefulSetStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+
+/// The ingester stsSpec: 3 replicas with a 10Gi `data` claim.
+pub fn sts_json() -> Value {
+    json!({
+        "replicas": 3,
+        "serviceName": "ingester",
+        "selector": { "matchLabels": { "app": "ingester" } },
+        "template": {
+            "metadata": { "labels": { "app": "ingester" } },
+            "spec": { "containers": [{ "name": "ingester", "image": "loki:2.0.0" }] },
+        },
+        "volumeClaimTemplates": [{
+            "metadata": { "name": "data" },
+            "spec": {
+                "accessModes": ["ReadWriteOnce"],
+                "resources": { "requests": { "storage": "10Gi" } },
+            },
+        }],
+    })
+}
+
+pub fn sts_spec(edit: impl Fn(&mut Value)) -> StatefulSetSpec {
+    let mut x = sts_json();
+    edit(&mut x);
+    serde_json::from_value(x).unwrap()
+}
+
+/// The ingester GratefulSet, in the loki namespace.
+pub fn gratefulset(edit: impl Fn(&mut Value)) -> GratefulSet {
+    let mut gs = GratefulSet::new(
+        "ingester",
+        GratefulSetSpec {
+            name: String::from("ingester"),
+            sts_spec: sts_spec(edit),
+            ..Default::default()
+        },
+    );
+    gs.metadata.namespace = Some(String::from("loki"));
+    gs
+}
+
+/// The pool of `gs`, created at `created` with `replicas` settled & confirmed replicas.
+pub fn found_pool(gs: &GratefulSet, replicas: i32, created: i64) -> GratefulSetPool {
+    let mut p = gs.pool();
+    p.spec.sts_spec.replicas = Some(replicas);
+    p.metadata.creation_timestamp = Some(Time(Utc.timestamp_opt(created, 0).unwrap()));
+    let config_hash = p.spec.config_hash();
+    p.status = Some(GratefulSetPoolStatus {
+        sts_status: StatefulSetStatus {
+            replicas,
+            ready_replicas: Some(replicas),
+            ..Default::default()
+        },
+        scale_up_records: (0..replicas).map(|o| (o, config_hash.clone())).collect(),
+        ..Default::default()
+    });
+    p
+}
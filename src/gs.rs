@@ -1,9 +1,14 @@
-use crate::manager::{error_policy, Data};
-use crate::{errors::*, gsp::*};
+use crate::conditions::{self, set_condition, Condition};
+use crate::diff::SpecDiff;
+use crate::hooks::{Hooks, HttpScaleDownSpec};
+use crate::manager::{controller_ref, error_policy, Data};
+use crate::plan::{Action, Budget, ObservedGratefulSet};
+use crate::{crd, errors::*, gsp::*, labels, volumes};
 use futures::{future::BoxFuture, FutureExt, StreamExt};
-use k8s_openapi::api::apps::v1::StatefulSetSpec;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1::CustomResourceDefinition;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetSpec};
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 use k8s_openapi::{Metadata, Resource};
 use kube::api::DeleteParams;
 use kube::api::ListParams;
@@ -18,6 +23,7 @@ use kube_runtime::controller::ReconcilerAction;
 use log::debug;
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::cmp::max;
 use std::time::Duration;
 
 #[derive(CustomResource, Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
@@ -25,59 +31,454 @@ use std::time::Duration;
     group = "pikach.us",
     version = "v1",
     kind = "GratefulSet",
+    apiextensions = "v1beta1",
     status = "GratefulSetStatus",
     shortname = "gs",
-    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas"}"#,
+    scale = r#"{"specReplicasPath":".spec.stsSpec.replicas", "statusReplicasPath":".status.replicas"}"#,
+    printcolumn = r#"{"name":"Replicas","type":"integer","jsonPath":".status.replicas"}"#,
+    printcolumn = r#"{"name":"Ready","type":"integer","jsonPath":".status.readyReplicas"}"#,
+    printcolumn = r#"{"name":"Updated","type":"integer","jsonPath":".status.updatedReplicas"}"#,
+    printcolumn = r#"{"name":"Pool","type":"string","jsonPath":".status.currentPoolHash"}"#,
     namespaced
 )]
+#[serde(rename_all = "camelCase")]
 pub struct GratefulSetSpec {
     pub name: String,
+    #[serde(alias = "sts_spec")]
     pub sts_spec: StatefulSetSpec,
+    /// Optional HTTP request run against a replica when it's retired,
+    /// e.g. `POST /ingester/shutdown` to flush Loki ingesters.
+    #[serde(default, alias = "scale_down", skip_serializing_if = "Option::is_none")]
+    pub scale_down: Option<HttpScaleDownSpec>,
+    /// Paths of additional stsSpec fields whose changes migrate to a new pool (as with the
+    /// immutable fields), e.g. `template.spec.containers[1].image` for a sidecar owning the
+    /// on-disk format.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub immutable_fields: Vec<String>,
+    /// How changes to immutable fields are rolled out: PoolMigration (the default) or Recreate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration_strategy: Option<MigrationStrategy>,
+    /// How fast replicas are migrated across pools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout_strategy: Option<RolloutStrategy>,
+    /// Stops moving replicas across pools until unset.
+    #[serde(default)]
+    pub paused: bool,
+    /// Aborts the migration in progress: replicas are moved back to the pool the GratefulSet was
+    /// migrating from (`status.previousPoolHash`), which is kept as is, and the new pool is deleted
+    /// once drained. Revert the spec before unsetting it, or the migration starts over.
+    #[serde(default)]
+    pub abort: bool,
 }
 
-impl GratefulSetSpec {
+/// RolloutStrategy controls the pace of migrations across pools. Both limits are either a number
+/// of replicas or a percentage of the desired replicas (e.g. `25%`).
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RolloutStrategy {
+    /// Replicas which may be unready during a migration, below the desired replicas.
+    /// Percentages round down. Defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<IntOrString>,
+    /// Replicas which may be added above the desired replicas during a migration.
+    /// Percentages round up. Defaults to 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_surge: Option<IntOrString>,
+}
+
+impl RolloutStrategy {
+    /// Resolves the limits against the desired replicas. As with deployments, maxUnavailable
+    /// is bumped to 1 when both are 0 so the migration can make progress.
+    pub fn budget(&self, desired: i32) -> std::result::Result<Budget, String> {
+        let resolve = |x: &Option<IntOrString>, default: i32, round_up: bool| match x {
+            None => Ok(default),
+            Some(IntOrString::Int(n)) if *n >= 0 => Ok(*n),
+            Some(IntOrString::String(s)) => s
+                .strip_suffix('%')
+                .and_then(|p| p.parse::<u32>().ok())
+                .map(|p| {
+                    let scaled = i64::from(p) * i64::from(max(desired, 0));
+                    let n = if round_up {
+                        (scaled + 99) / 100
+                    } else {
+                        scaled / 100
+                    };
+                    n.min(i64::from(i32::MAX)) as i32
+                })
+                .ok_or_else(|| {
+                    format!("invalid value {:?}: must be an integer or a percentage", s)
+                }),
+            Some(IntOrString::Int(n)) => Err(format!("invalid value {}: must not be negative", n)),
+        };
+        let max_surge = resolve(&self.max_surge, 0, true)?;
+        let max_unavailable = resolve(&self.max_unavailable, 1, false)?;
+        Ok(Budget {
+            max_unavailable: if max_surge == 0 && max_unavailable == 0 {
+                1
+            } else {
+                max_unavailable
+            },
+            max_surge,
+        })
+    }
+}
+
+impl GratefulSet {
+    // hash of the immutable fields of the current spec, identifying the desired pool.
+    pub fn pool_hash(&self) -> String {
+        self.pool().hash()
+    }
+
+    // returns the desired pool for the current spec.
     pub fn pool(&self) -> GratefulSetPool {
-        let name = format!(
-            "{}-{:x}",
-            self.name,
-            ImmutableSts(&self.sts_spec).checksum()
-        );
+        let spec = GratefulSetPoolSpec {
+            sts_spec: self.spec.sts_spec.clone(),
+            scale_down: self.spec.scale_down.clone(),
+            immutable_fields: self.spec.immutable_fields.clone(),
+            migration_strategy: self.spec.migration_strategy,
+            ..Default::default()
+        };
+        let collision_count = self
+            .status
+            .as_ref()
+            .and_then(|x| x.collision_count)
+            .unwrap_or(0);
+        let hash = ImmutableSts(&spec.sts_spec, &spec.immutable_fields).hash(collision_count);
+        let name = format!("{}-{}", self.spec.name, hash);
         let mut want = GratefulSetPool::new(
             &name,
             GratefulSetPoolSpec {
                 name: name.clone(),
-                sts_spec: self.sts_spec.clone(),
-                ..Default::default()
+                ..spec
             },
         );
 
         // Set owner reference and label pointing to gratefulset
-        let mut want_md: &mut ObjectMeta = want.metadata_mut();
-        want_md.owner_references = Some(vec![OwnerReference {
-            kind: GratefulSet::KIND.to_string(),
-            ..Default::default()
-        }]);
-        let mut labels = std::collections::BTreeMap::new();
-        labels.insert(String::from("owner.pikach.us"), String::from(name));
-        want_md.labels = Some(labels);
+        let want_md: &mut ObjectMeta = want.metadata_mut();
+        want_md.namespace = Meta::namespace(self);
+        want_md.owner_references = Some(vec![controller_ref(self)]);
+        want_md.labels = Some(labels::pool_labels(&Meta::name(self), &hash));
 
         want
     }
+
+    // returns the found pool running the immutable fields of the current spec, if any,
+    // including a pool with less claim storage, which is grown in place.
+    // Fields are compared rather than hashes, so pools named by an older hash scheme are
+    // adopted instead of migrated away from.
+    // With the Recreate strategy, the newest pool is updated in place instead, as long as its
+    // pods & PVCs can be adopted.
+    pub fn current_pool<'a>(
+        &self,
+        found_pools: &'a [GratefulSetPool],
+    ) -> Option<&'a GratefulSetPool> {
+        let want = self.pool();
+        found_pools
+            .iter()
+            .find(|p| p.spec.adopts(&want.spec))
+            .or_else(|| {
+                if self.spec.migration_strategy != Some(MigrationStrategy::Recreate) {
+                    return None;
+                }
+                // Storage can only be grown in place, shrinking it requires a new pool.
+                found_pools
+                    .iter()
+                    .filter(|p| !volumes::shrinks(&want.spec.sts_spec, &p.spec.sts_spec))
+                    .filter(|p| p.spec.sts_spec.selector == want.spec.sts_spec.selector)
+                    .max_by_key(|p| p.metadata.creation_timestamp.clone())
+            })
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct GratefulSetStatus {
-    /// currentReplicas is the number of Pods created by the StatefulSet controller from the StatefulSet version indicated by currentRevision.
+    /// currentReplicas is the number of Pods created by the StatefulSet controllers from the StatefulSet versions indicated by currentRevision, across all pools.
+    #[serde(alias = "current_replicas")]
     pub current_replicas: Option<i32>,
 
-    /// readyReplicas is the number of Pods created by the StatefulSet controller that have a Ready Condition.
+    /// readyReplicas is the number of Pods created by the StatefulSet controllers that have a Ready Condition, across all pools.
+    #[serde(alias = "ready_replicas")]
     pub ready_replicas: Option<i32>,
 
-    /// replicas is the number of Pods created by the StatefulSet controller.
+    /// replicas is the number of Pods created by the StatefulSet controllers, across all pools.
     pub replicas: i32,
 
-    /// updatedReplicas is the number of Pods created by the StatefulSet controller from the StatefulSet version indicated by updateRevision.
+    /// updatedReplicas is the number of Pods in the current pool created from the StatefulSet version indicated by updateRevision, i.e. Pods running the desired spec.
+    #[serde(alias = "updated_replicas")]
     pub updated_replicas: Option<i32>,
+
+    /// currentPoolHash is the hash of the pool matching the current spec.
+    #[serde(alias = "current_pool_hash")]
+    pub current_pool_hash: Option<String>,
+
+    /// observedGeneration is the most recent generation observed by the controller.
+    #[serde(alias = "observed_generation")]
+    pub observed_generation: Option<i64>,
+
+    /// collisionCount salts the pool hash when it collides with an existing pool of a different spec.
+    pub collision_count: Option<i32>,
+
+    /// previousPoolHash is the hash of the pool matching the spec before the current one, which
+    /// an aborted migration moves back to.
+    pub previous_pool_hash: Option<String>,
+
+    /// pools is the per pool breakdown of replicas.
+    #[serde(default)]
+    pub pools: Vec<PoolStatus>,
+
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStatus {
+    pub name: String,
+    pub hash: String,
+    /// desired replicas of the pool.
+    pub desired_replicas: i32,
+    pub replicas: i32,
+    pub ready_replicas: i32,
+    pub current_replicas: i32,
+    pub updated_replicas: i32,
+}
+
+impl GratefulSet {
+    // The pool hash before the current one: the previously current pool's, once the current
+    // pool changes. It's kept while aborting, so reverting the spec doesn't change course.
+    fn previous_pool_hash(&self, current_pool_hash: &str) -> Option<String> {
+        let status = self.status.clone().unwrap_or_default();
+        match status.current_pool_hash {
+            Some(hash) if hash != current_pool_hash && !self.spec.abort => Some(hash),
+            _ => status.previous_pool_hash,
+        }
+    }
+
+    fn current_pool_hash(&self, found_pools: &[GratefulSetPool]) -> String {
+        self.current_pool(found_pools)
+            .map(|p| p.hash())
+            .unwrap_or_else(|| self.pool_hash())
+    }
+
+    // returns the found pool an aborted migration moves back to, if it's still around.
+    pub fn previous_pool<'a>(
+        &self,
+        found_pools: &'a [GratefulSetPool],
+    ) -> Option<&'a GratefulSetPool> {
+        let hash = self.previous_pool_hash(&self.current_pool_hash(found_pools))?;
+        found_pools.iter().find(|p| p.hash() == hash)
+    }
+
+    // aggregates the status of all pools belonging to this GratefulSet.
+    pub fn aggregate_status(&self, found_pools: &[GratefulSetPool]) -> GratefulSetStatus {
+        let current_pool_hash = self.current_pool_hash(found_pools);
+        let mut pools: Vec<PoolStatus> = found_pools
+            .iter()
+            .map(|p| {
+                let sts_status = p.status.clone().unwrap_or_default().sts_status;
+                PoolStatus {
+                    name: Meta::name(p),
+                    hash: p.hash(),
+                    desired_replicas: p.spec.sts_spec.replicas.unwrap_or(1),
+                    replicas: sts_status.replicas,
+                    ready_replicas: sts_status.ready_replicas.unwrap_or(0),
+                    current_replicas: sts_status.current_replicas.unwrap_or(0),
+                    updated_replicas: sts_status.updated_replicas.unwrap_or(0),
+                }
+            })
+            .collect();
+        pools.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let sum = |f: fn(&PoolStatus) -> i32| pools.iter().map(f).sum::<i32>();
+        let updated_replicas = pools
+            .iter()
+            .filter(|p| p.hash == current_pool_hash)
+            .map(|p| p.updated_replicas)
+            .sum();
+        let conditions = self.conditions(
+            found_pools,
+            &current_pool_hash,
+            sum(|p| p.ready_replicas),
+            updated_replicas,
+            pools
+                .iter()
+                .filter(|p| p.hash != current_pool_hash)
+                .map(|p| p.replicas)
+                .sum(),
+        );
+        GratefulSetStatus {
+            current_replicas: Some(sum(|p| p.current_replicas)),
+            ready_replicas: Some(sum(|p| p.ready_replicas)),
+            replicas: sum(|p| p.replicas),
+            updated_replicas: Some(updated_replicas),
+            previous_pool_hash: self.previous_pool_hash(&current_pool_hash),
+            current_pool_hash: Some(current_pool_hash),
+            observed_generation: self.metadata.generation,
+            collision_count: self.status.as_ref().and_then(|x| x.collision_count),
+            pools,
+            conditions,
+        }
+    }
+
+    // Derives the GratefulSet conditions from its pools.
+    fn conditions(
+        &self,
+        pools: &[GratefulSetPool],
+        current_pool_hash: &str,
+        ready: i32,
+        updated: i32,
+        old_replicas: i32,
+    ) -> Vec<Condition> {
+        let mut conds = self.status.clone().unwrap_or_default().conditions;
+        let desired = self.spec.sts_spec.replicas.unwrap_or(1);
+        // pools which have the given condition set, along with its message.
+        let with = |type_: &str| -> Vec<String> {
+            pools
+                .iter()
+                .filter_map(|p| {
+                    let pool_conds = p.status.clone().unwrap_or_default().conditions;
+                    conditions::find(&pool_conds, type_)
+                        .filter(|c| c.status == "True")
+                        .map(|c| {
+                            format!(
+                                "{}: {}",
+                                Meta::name(p),
+                                c.message.clone().unwrap_or_default()
+                            )
+                        })
+                })
+                .collect()
+        };
+
+        set_condition(
+            &mut conds,
+            if ready >= desired {
+                Condition::new(
+                    conditions::AVAILABLE,
+                    true,
+                    "MinimumReplicasAvailable",
+                    format!("{}/{} replicas ready", ready, desired),
+                )
+            } else {
+                Condition::new(
+                    conditions::AVAILABLE,
+                    false,
+                    "MinimumReplicasUnavailable",
+                    format!("{}/{} replicas ready", ready, desired),
+                )
+            },
+        );
+
+        let progressing = with(conditions::PROGRESSING);
+        set_condition(
+            &mut conds,
+            if self.spec.paused {
+                Condition::new(
+                    conditions::PROGRESSING,
+                    false,
+                    "Paused",
+                    format!("paused with {} replicas in old pools", old_replicas),
+                )
+            } else if let Some(previous) = self.previous_pool(pools).filter(|_| self.spec.abort) {
+                let elsewhere: i32 = pools
+                    .iter()
+                    .filter(|p| p.hash() != previous.hash())
+                    .map(|p| p.status.clone().unwrap_or_default().sts_status.replicas)
+                    .sum();
+                Condition::new(
+                    conditions::PROGRESSING,
+                    elsewhere > 0,
+                    if elsewhere > 0 {
+                        "RollingBack"
+                    } else {
+                        "RollbackComplete"
+                    },
+                    format!(
+                        "{} replicas left to move back to pool {}",
+                        elsewhere,
+                        previous.hash()
+                    ),
+                )
+            } else if old_replicas > 0 {
+                Condition::new(
+                    conditions::PROGRESSING,
+                    true,
+                    "PoolMigration",
+                    format!(
+                        "migrating {} replicas to pool {}",
+                        old_replicas, current_pool_hash
+                    ),
+                )
+            } else if updated != desired || !progressing.is_empty() {
+                Condition::new(
+                    conditions::PROGRESSING,
+                    true,
+                    "Scaling",
+                    format!("{}/{} replicas updated", updated, desired),
+                )
+            } else {
+                Condition::new(
+                    conditions::PROGRESSING,
+                    false,
+                    "RolloutComplete",
+                    format!("{} replicas updated", updated),
+                )
+            },
+        );
+
+        // These are true whenever any pool reports them.
+        for (type_, true_reason, false_reason) in &[
+            (
+                conditions::SCALING_DOWN,
+                "PoolScalingDown",
+                "NoPoolScalingDown",
+            ),
+            (conditions::HOOK_FAILED, "PoolHookFailed", "NoHookFailures"),
+            (conditions::DEGRADED, "PoolDegraded", "PoolsHealthy"),
+        ] {
+            let msgs = with(type_);
+            let reason = if msgs.is_empty() {
+                false_reason
+            } else {
+                true_reason
+            };
+            set_condition(
+                &mut conds,
+                Condition::new(type_, !msgs.is_empty(), reason, msgs.join("; ")),
+            );
+        }
+        conds
+    }
+}
+
+// Server-side applies a pool's spec, along with the labels & owner reference tying it to `gs`.
+// This creates the pool if it doesn't exist yet.
+async fn apply_pool(
+    pools: &Api<GratefulSetPool>,
+    gs: &GratefulSet,
+    pool: &GratefulSetPool,
+) -> Result<GratefulSetPool> {
+    let mut pool_labels = pool.child_labels();
+    pool_labels.insert(String::from(labels::OWNER), Meta::name(gs));
+    let patch = serde_json::to_vec(&serde_json::json!({
+        "apiVersion": GratefulSetPool::API_VERSION,
+        "kind": GratefulSetPool::KIND,
+        "metadata": {
+            "name": Meta::name(pool),
+            "labels": pool_labels,
+            "ownerReferences": [controller_ref(gs)],
+        },
+        "spec": pool.spec,
+    }))?;
+    pools
+        .patch(
+            &Meta::name(pool),
+            &PatchParams::apply("gratefulset-mgr"),
+            patch,
+        )
+        .await
+        .map_err(|e| Error::with_chain(e, format!("applying gratefulsetpool {}", Meta::name(pool))))
 }
 
 async fn reconcile(gs: GratefulSet, ctx: Context<Data>) -> Result<ReconcilerAction> {
@@ -86,152 +487,94 @@ async fn reconcile(gs: GratefulSet, ctx: Context<Data>) -> Result<ReconcilerActi
     let ns = Meta::namespace(&gs).expect("gs is namespaced");
     debug!("Reconcile Foo {}: {:?}", name, gs);
 
+    let gss: Api<GratefulSet> = Api::namespaced(client.clone(), &ns);
     let pools: Api<GratefulSetPool> = Api::namespaced(client.clone(), &ns);
     let lp = ListParams {
-        label_selector: Some(format!("owner.pikach.us={}", name)),
+        label_selector: Some(labels::owner_selector(&name)),
         ..ListParams::default()
     };
 
-    // Fetch all pools belonging to this GratefulSet and
-    // separate into ([old_pool], desired_pool)
-    let mut want = gs.spec.pool();
-    // Default a potentially new pool to 0 replicas (scaling is handled independently).
-    want.spec.sts_spec.replicas = Some(0);
-    let desired_hash = ImmutableSts(&want.spec.sts_spec).checksum();
-    // If the desired pool does not exist, we'll want to create it starting at 0 replicas.
-
-    let (old_pools, cur_pool): (Vec<GratefulSetPool>, GratefulSetPool) =
-        pools.list(&lp).await?.into_iter().fold(
-            (vec![], want.clone()),
-            move |(mut old_pools, mut cur_pool), p| {
-                let hash = ImmutableSts(&p.spec.sts_spec).checksum();
-                if desired_hash != hash {
-                    old_pools.push(p);
-                } else {
-                    cur_pool = p;
-                }
-                (old_pools, cur_pool)
-            },
-        );
+    let found_pools = pools.list(&lp).await?.items;
 
-    // If only the desired pool exists & it has the correct config & replicas,
-    // ensure any old pools are deleted then bail.
-    if cur_pool.spec.sts_spec == gs.spec.sts_spec {
-        for p in old_pools {
-            pools
-                .delete(&Meta::name(&p), &DeleteParams::default())
-                .await?;
-        }
+    // Report the aggregated state of all pools.
+    let status = gs.aggregate_status(&found_pools);
+    if gs.status.as_ref() != Some(&status) {
+        let patch = serde_json::to_vec(&serde_json::json!({ "status": status }))?;
+        gss.patch_status(&name, &PatchParams::default(), patch)
+            .await
+            .map_err(|e| Error::with_chain(e, "updating gratefulset status"))?;
+    }
+
+    let actions = ObservedGratefulSet {
+        gs: &gs,
+        pools: &found_pools,
+    }
+    .plan();
+    if actions.is_empty() {
         return Ok(ReconcilerAction {
             requeue_after: None,
         });
     }
 
-    // If the desired pool exists but has a different spec (sans replicas), update it and return early. We'll need to wait for the underlying sts to roll to the new spec before continuing.
-    if cur_pool.spec.sts_spec.replicas > Some(0)
-        && without_replicas(&want.spec.sts_spec) == without_replicas(&cur_pool.spec.sts_spec)
-    {
-        // Don't update the replicas; those will be scaled independently once thew new spec settles.
-        let mut diff = cur_pool.clone();
-        diff.spec = want.spec.clone();
-        diff.spec.sts_spec.replicas = cur_pool.spec.sts_spec.replicas;
-
-        let serialized = serde_json::to_string(&diff)?;
-        let patch = serde_yaml::to_vec(&serialized)?;
-
-        return pools
-            .patch(
-                &Meta::name(&cur_pool),
-                &PatchParams::apply("gratefulset-mgr"),
-                patch,
-            )
-            .await
-            .map_err(|e| Error::with_chain(e, "something went wrong"))
-            .map(|_| ReconcilerAction {
-                requeue_after: None,
-            });
-    }
-
-    // If we've gotten this far, we're assured that the current pool has the correct spec, but
-    // there may be older pools still around and the current pool may not have the correct replica count.
-
-    let total_ready = old_pools.iter().fold(
-        cur_pool
-            .clone()
-            .status
-            .and_then(|s| s.sts_status.ready_replicas)
-            .unwrap_or(0),
-        |total_ready, x| {
-            total_ready
-                + x.status
-                    .as_ref()
-                    .and_then(|s| s.sts_status.ready_replicas)
-                    .unwrap_or(0)
-        },
-    );
-    let total_desired = gs.spec.sts_spec.replicas.unwrap_or(1);
-
-    // Now we have to handle a few cases for scaling:
-    // Order of operations should be (ScaleDown -> ScaleUp)
-
-    // If replicas across all pools >= desired replicas,
-    // remove one from the most out of date pool (ScaleDown). This
-    // mimics the statefulset rollout semantics where
-    // one is removed before adding a new revision replica.
-    if total_ready >= total_desired {
-        // remove one from the oldest possible pool
-        let delta_pool = old_pools
+    let want = gs.pool();
+    let found = |pool: &str| {
+        found_pools
             .iter()
-            .fold(None, |acc, x| {
-                acc.or_else(|| {
-                    let reps = x.spec.sts_spec.replicas.unwrap_or(1);
-                    if reps > 0 {
-                        let mut updated = x.clone();
-                        updated.spec.sts_spec.replicas = Some(reps - 1);
+            .find(|p| Meta::name(*p) == pool)
+            .cloned()
+            .expect("planned pool exists")
+    };
+    for action in actions {
+        info!("GratefulSet {}: {}", name, action);
+        match action {
+            // The desired pool's name is taken by a pool of a different spec: salt the hash & try again.
+            Action::BumpCollisionCount => {
+                let patch = serde_json::to_vec(&serde_json::json!({
+                    "status": { "collisionCount": status.collision_count.unwrap_or(0) + 1 }
+                }))?;
+                gss.patch_status(&name, &PatchParams::default(), patch)
+                    .await
+                    .map_err(|e| Error::with_chain(e, "updating gratefulset collisionCount"))?;
+                return Ok(ReconcilerAction {
+                    requeue_after: Some(Duration::from_secs(1)),
+                });
+            }
+            Action::CreatePool { replicas, .. } => {
+                let mut x = want.clone();
+                x.spec.sts_spec.replicas = Some(replicas);
+                apply_pool(&pools, &gs, &x).await?;
+            }
+            // Don't update the replicas; those will be scaled independently once the new spec settles.
+            Action::UpdatePool { pool } => {
+                let cur = found(&pool);
+                debug!(
+                    "GratefulSet {} differs from pool {}: {}",
+                    name,
+                    pool,
+                    SpecDiff::new(&gs.spec.sts_spec, &cur.spec.sts_spec)
+                );
+                let mut x = cur.clone();
+                x.spec = GratefulSetPoolSpec {
+                    name: cur.spec.name.clone(),
+                    ..want.spec.clone()
+                };
+                x.spec.sts_spec.replicas = cur.spec.sts_spec.replicas;
+                apply_pool(&pools, &gs, &x).await?;
+            }
+            Action::SetReplicas { pool, replicas } => {
+                let mut x = found(&pool);
+                x.spec.sts_spec.replicas = Some(replicas);
+                apply_pool(&pools, &gs, &x).await?;
+            }
+            Action::DeletePool { pool } => {
+                match pools.delete(&pool, &DeleteParams::default()).await {
+                    Err(kube::Error::Api(e)) if e.code == 404 => {}
+                    res => {
+                        res.map_err(|e| Error::with_chain(e, "deleting gratefulsetpool"))?;
                     }
-                    return None;
-                })
-            })
-            // default to using the most recent pool if the previous pools don't exist
-            // or have replicas set to 0.
-            .unwrap_or_else(|| {
-                let mut x = cur_pool.clone();
-                x.spec.delta_replicas(-1);
-                x
-            });
-
-        let serialized = serde_json::to_string(&delta_pool)?;
-        let patch = serde_yaml::to_vec(&serialized)?;
-        pools
-            .patch(
-                &Meta::name(&delta_pool),
-                &PatchParams::apply("gratefulset-mgr"),
-                patch,
-            )
-            .await
-            .map_err(|e| Error::with_chain(e, "something went wrong"))
-            .map(|_| ReconcilerAction {
-                requeue_after: None,
-            })?;
-    } else {
-        // If replicas across all pools < desired replicas,
-        // add one to desired pool (ScaleUp).
-
-        let mut diff = cur_pool.clone();
-        diff.spec.delta_replicas(1);
-        let serialized = serde_json::to_string(&diff)?;
-        let patch = serde_yaml::to_vec(&serialized)?;
-        pools
-            .patch(
-                &Meta::name(&diff),
-                &PatchParams::apply("gratefulset-mgr"),
-                patch,
-            )
-            .await
-            .map_err(|e| Error::with_chain(e, "something went wrong"))
-            .map(|_| ReconcilerAction {
-                requeue_after: None,
-            })?;
+                }
+            }
+        }
     }
 
     Ok(ReconcilerAction {
@@ -242,39 +585,303 @@ async fn reconcile(gs: GratefulSet, ctx: Context<Data>) -> Result<ReconcilerActi
 
 pub struct Manager {}
 
-/// Example Manager that owns a Controller for Foo
+/// Manager owns the Controllers for GratefulSets and GratefulSetPools
 impl Manager {
     /// Lifecycle initialization interface for app
     ///
-    /// This returns a `Manager` that drives a `Controller` + a future to be awaited
-    /// It is up to `main` to wait for the controller stream.
-    pub async fn new(client: Client) -> (Self, BoxFuture<'static, ()>) {
+    /// This returns a `Manager` that drives both `Controller`s + a future to be awaited
+    /// It is up to `main` to wait for the controller streams.
+    pub async fn new(client: Client, hooks: Hooks) -> (Self, BoxFuture<'static, ()>) {
         let context = Context::new(Data {
             client: client.clone(),
+            hooks,
         });
-        let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-        crds.get("gratefulset.pikach.us")
-            .await
-            .expect("install gratefulset crd first");
-
-        crds.get("gratefulsetpool.pikach.us")
+        crd::check(client.clone())
             .await
-            .expect("install gratefulsetpool crd first");
+            .expect("install gratefulset crds first");
 
         let gs = Api::<GratefulSet>::all(client.clone());
         let pools = Api::<GratefulSetPool>::all(client.clone());
+        let sts = Api::<StatefulSet>::all(client.clone());
+        let configmaps = Api::<ConfigMap>::all(client.clone());
 
-        let drainer = Controller::new(gs, ListParams::default())
-            .owns(pools, ListParams::default())
-            .run(reconcile, error_policy, context)
+        // Only watch children carrying the owner label, rather than every statefulset &
+        // configmap in the cluster.
+        let owned = || ListParams::default().labels(labels::OWNER);
+        let gs_drainer = Controller::new(gs, ListParams::default())
+            .owns(pools.clone(), owned())
+            .run(reconcile, error_policy, context.clone())
             .for_each(|o| {
                 info!("Reconciled {:?}", o);
                 futures::future::ready(())
-            })
+            });
+
+        let pool_drainer = Controller::new(pools, ListParams::default())
+            .owns(sts, owned())
+            .owns(configmaps, owned())
+            .run(crate::gsp::reconcile, error_policy, context)
+            .for_each(|o| {
+                info!("Reconciled {:?}", o);
+                futures::future::ready(())
+            });
+        // what we do with the controller streams from .run() ^^ does not matter
+        // but we do need to consume them, hence general printing + return future
+
+        let drainer = futures::future::join(gs_drainer, pool_drainer)
+            .map(|_| ())
             .boxed();
-        // what we do with the controller stream from .run() ^^ does not matter
-        // but we do need to consume it, hence general printing + return future
 
         (Self {}, drainer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, found_pool};
+    use serde_json::json;
+
+    // A pool of `gs` with `replicas`, of which `ready` are ready & `updated` run its spec.
+    fn pool(gs: &GratefulSet, replicas: i32, ready: i32, updated: i32) -> GratefulSetPool {
+        let mut p = found_pool(gs, replicas, 0);
+        let sts_status = &mut p.status.as_mut().unwrap().sts_status;
+        sts_status.ready_replicas = Some(ready);
+        sts_status.current_replicas = Some(replicas);
+        sts_status.updated_replicas = Some(updated);
+        p
+    }
+
+    // The ingester, migrating from a pool with another serviceName.
+    fn migrating() -> (GratefulSet, GratefulSet) {
+        let old = fixtures::gratefulset(|x| x["serviceName"] = json!("ingester-old"));
+        let mut gs = fixtures::gratefulset(|_| {});
+        gs.status = Some(GratefulSetStatus {
+            current_pool_hash: Some(old.pool_hash()),
+            ..Default::default()
+        });
+        (gs, old)
+    }
+
+    #[test]
+    fn aggregates_pool_statuses() {
+        let (gs, old) = migrating();
+        // (case, pools, replicas, ready, current, updated)
+        let cases = vec![
+            ("no pools", vec![], 0, 0, 0, 0),
+            ("old pool only", vec![pool(&old, 3, 3, 3)], 3, 3, 3, 0),
+            (
+                "migrating",
+                vec![pool(&old, 2, 2, 2), pool(&gs, 2, 1, 2)],
+                4,
+                3,
+                4,
+                2,
+            ),
+        ];
+        for (name, pools, replicas, ready, current, updated) in cases {
+            let status = gs.aggregate_status(&pools);
+            assert_eq!(status.replicas, replicas, "{}", name);
+            assert_eq!(status.ready_replicas, Some(ready), "{}", name);
+            assert_eq!(status.current_replicas, Some(current), "{}", name);
+            // only the current pool's replicas run the desired spec.
+            assert_eq!(status.updated_replicas, Some(updated), "{}", name);
+            assert_eq!(status.current_pool_hash, Some(gs.pool_hash()), "{}", name);
+            // the pool it's migrating from.
+            assert_eq!(status.previous_pool_hash, Some(old.pool_hash()), "{}", name);
+
+            let mut names: Vec<String> = pools.iter().map(Meta::name).collect();
+            names.sort();
+            let found: Vec<String> = status.pools.iter().map(|p| p.name.clone()).collect();
+            assert_eq!(found, names, "{}", name);
+        }
+    }
+
+    #[test]
+    fn reports_conditions() {
+        let (gs, old) = migrating();
+        let up_to_date = || {
+            let mut gs = gs.clone();
+            gs.status = None;
+            gs
+        };
+        let retiring = {
+            let mut p = pool(&gs, 3, 3, 3);
+            p.status.as_mut().unwrap().conditions = vec![
+                Condition::new(conditions::PROGRESSING, true, "Scaling", "from 3 to 2"),
+                Condition::new(
+                    conditions::SCALING_DOWN,
+                    true,
+                    "RetiringReplica",
+                    "retiring replica 2",
+                ),
+            ];
+            p
+        };
+        // (case, gratefulset, pools, expected (type, status, reason) of some conditions)
+        let cases = vec![
+            (
+                "rolled out",
+                up_to_date(),
+                vec![pool(&gs, 3, 3, 3)],
+                vec![
+                    (conditions::AVAILABLE, true, "MinimumReplicasAvailable"),
+                    (conditions::PROGRESSING, false, "RolloutComplete"),
+                    (conditions::SCALING_DOWN, false, "NoPoolScalingDown"),
+                ],
+            ),
+            (
+                "scaling up",
+                up_to_date(),
+                vec![pool(&gs, 2, 2, 2)],
+                vec![
+                    (conditions::AVAILABLE, false, "MinimumReplicasUnavailable"),
+                    (conditions::PROGRESSING, true, "Scaling"),
+                ],
+            ),
+            (
+                "scaling down",
+                up_to_date(),
+                vec![retiring],
+                vec![
+                    (conditions::PROGRESSING, true, "Scaling"),
+                    (conditions::SCALING_DOWN, true, "PoolScalingDown"),
+                ],
+            ),
+            (
+                "migrating",
+                gs.clone(),
+                vec![pool(&old, 2, 2, 2), pool(&gs, 1, 1, 1)],
+                vec![
+                    (conditions::AVAILABLE, true, "MinimumReplicasAvailable"),
+                    (conditions::PROGRESSING, true, "PoolMigration"),
+                ],
+            ),
+            (
+                "paused",
+                {
+                    let mut gs = gs.clone();
+                    gs.spec.paused = true;
+                    gs
+                },
+                vec![pool(&old, 2, 2, 2), pool(&gs, 1, 1, 1)],
+                vec![(conditions::PROGRESSING, false, "Paused")],
+            ),
+            (
+                "rolling back",
+                {
+                    let mut gs = gs.clone();
+                    gs.spec.abort = true;
+                    gs.status.as_mut().unwrap().previous_pool_hash = Some(old.pool_hash());
+                    gs
+                },
+                vec![pool(&old, 2, 2, 2), pool(&gs, 1, 1, 1)],
+                vec![(conditions::PROGRESSING, true, "RollingBack")],
+            ),
+        ];
+        for (name, gs, pools, want) in cases {
+            let conds = gs.aggregate_status(&pools).conditions;
+            for (type_, status, reason) in want {
+                let cond = conditions::find(&conds, type_).unwrap();
+                assert_eq!(
+                    (cond.status == "True", cond.reason.as_deref()),
+                    (status, Some(reason)),
+                    "{}: {}",
+                    name,
+                    type_
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reads_snake_case_fields() {
+        // Objects written before the fields were renamed to camelCase.
+        let gs: GratefulSet = serde_json::from_value(serde_json::json!({
+            "apiVersion": "pikach.us/v1",
+            "kind": "GratefulSet",
+            "metadata": { "name": "ingester" },
+            "spec": {
+                "name": "ingester",
+                "sts_spec": { "replicas": 3, "selector": {}, "serviceName": "ingester", "template": {} },
+                "scale_down": { "path": "/ingester/shutdown", "port": 3100 },
+            },
+            "status": { "replicas": 3, "ready_replicas": 2, "current_pool_hash": "abc" },
+        }))
+        .unwrap();
+        assert_eq!(gs.spec.sts_spec.replicas, Some(3));
+        assert_eq!(gs.spec.scale_down.unwrap().port, 3100);
+        let status = gs.status.unwrap();
+        assert_eq!(status.ready_replicas, Some(2));
+        assert_eq!(status.current_pool_hash.as_deref(), Some("abc"));
+
+        let gsp: GratefulSetPoolStatus = serde_json::from_value(serde_json::json!({
+            "sts_status": { "replicas": 2 },
+            "scale_down_records": { "1": "abc" },
+        }))
+        .unwrap();
+        assert_eq!(gsp.sts_status.replicas, 2);
+        assert_eq!(
+            gsp.scale_down_records.get(&1).map(String::as_str),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn rollout_budgets() {
+        let strategy =
+            |unavailable: Option<IntOrString>, surge: Option<IntOrString>| RolloutStrategy {
+                max_unavailable: unavailable,
+                max_surge: surge,
+            };
+        let percent = |s: &str| Some(IntOrString::String(String::from(s)));
+        let budget = |max_unavailable, max_surge| {
+            Ok(Budget {
+                max_unavailable,
+                max_surge,
+            })
+        };
+
+        assert_eq!(RolloutStrategy::default().budget(10), budget(1, 0));
+        assert_eq!(
+            strategy(Some(IntOrString::Int(3)), Some(IntOrString::Int(2))).budget(10),
+            budget(3, 2)
+        );
+        // maxUnavailable rounds down, maxSurge rounds up.
+        assert_eq!(
+            strategy(percent("25%"), percent("25%")).budget(10),
+            budget(2, 3)
+        );
+        // some replica must be able to move.
+        assert_eq!(
+            strategy(percent("5%"), Some(IntOrString::Int(0))).budget(10),
+            budget(1, 0)
+        );
+        assert!(strategy(percent("lots"), None).budget(10).is_err());
+        assert!(strategy(Some(IntOrString::Int(-1)), None)
+            .budget(10)
+            .is_err());
+    }
+
+    #[test]
+    fn tracks_the_previous_pool() {
+        let status = |current: &str, previous: Option<&str>| GratefulSetStatus {
+            current_pool_hash: Some(String::from(current)),
+            previous_pool_hash: previous.map(String::from),
+            ..Default::default()
+        };
+        let mut gs = GratefulSet::new("ingester", GratefulSetSpec::default());
+        let hash = gs.pool_hash();
+
+        gs.status = Some(status(&hash, None));
+        assert_eq!(gs.previous_pool_hash(&hash), None);
+        // the current pool changes
+        gs.status = Some(status("a", None));
+        assert_eq!(gs.previous_pool_hash(&hash), Some(String::from("a")));
+        gs.status = Some(status(&hash, Some("a")));
+        assert_eq!(gs.previous_pool_hash(&hash), Some(String::from("a")));
+        // reverting the spec while aborting doesn't change course.
+        gs.spec.abort = true;
+        gs.status = Some(status("b", Some("a")));
+        assert_eq!(gs.previous_pool_hash(&hash), Some(String::from("a")));
+    }
+}
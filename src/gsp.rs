@@ -1,28 +1,32 @@
+use crate::conditions::{self, set_condition, Condition};
+use crate::defaults;
+use crate::diff::{self, SpecDiff, MUTABLE};
 use crate::errors::*;
-use crate::manager::Data;
+use crate::hooks::{Hooks, HttpScaleDownSpec};
+use crate::labels;
+use crate::manager::{controller_ref, Data};
+use crate::plan::{ObservedPool, PoolAction};
+use crate::volumes;
 use k8s_openapi::api::apps::v1::StatefulSet;
 use k8s_openapi::api::apps::v1::{StatefulSetSpec, StatefulSetStatus};
 use k8s_openapi::api::core::v1::ConfigMap;
 use k8s_openapi::api::core::v1::ConfigMapVolumeSource;
 use k8s_openapi::api::core::v1::Container;
-use k8s_openapi::api::core::v1::PodSpec;
 use k8s_openapi::api::core::v1::Volume;
 use k8s_openapi::api::core::v1::VolumeMount;
+use k8s_openapi::api::core::v1::{Pod, PodSpec};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
-use k8s_openapi::{Metadata, Resource};
 use kube::api::Meta;
-use kube::api::{ListParams, PatchParams};
+use kube::api::{DeleteParams, ListParams, PatchParams, PostParams, PropagationPolicy};
 use kube::Api;
 use kube_derive::CustomResource;
 use kube_runtime::controller::Context;
 use kube_runtime::controller::ReconcilerAction;
-use log::debug;
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
-use std::cmp::max;
+use serde_json::Value;
 use std::cmp::min;
 use std::collections::BTreeMap;
-use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
 use std::time::Duration;
 
@@ -31,14 +35,40 @@ use std::time::Duration;
     group = "pikach.us",
     version = "v1",
     kind = "GratefulSetPool",
+    apiextensions = "v1beta1",
     status = "GratefulSetPoolStatus",
     shortname = "gsp",
-    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas"}"#,
+    scale = r#"{"specReplicasPath":".spec.stsSpec.replicas", "statusReplicasPath":".status.stsStatus.replicas"}"#,
     namespaced
 )]
+#[serde(rename_all = "camelCase")]
 pub struct GratefulSetPoolSpec {
     pub name: String,
+    #[serde(alias = "sts_spec")]
     pub sts_spec: StatefulSetSpec,
+    /// Optional HTTP request run against a replica when it's retired.
+    #[serde(default, alias = "scale_down", skip_serializing_if = "Option::is_none")]
+    pub scale_down: Option<HttpScaleDownSpec>,
+    /// Paths of additional stsSpec fields whose changes create a new pool.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub immutable_fields: Vec<String>,
+    /// How changes to immutable fields are rolled out, defaults to PoolMigration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration_strategy: Option<MigrationStrategy>,
+}
+
+/// MigrationStrategy decides how changes to the immutable fields of a GratefulSet are rolled out.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum MigrationStrategy {
+    /// Migrate the replicas one by one to a new pool (& statefulset) with the new spec,
+    /// running the ScaleDown & ScaleUp hooks for each.
+    PoolMigration,
+    /// Update the current pool in place: its statefulset is deleted with orphan propagation
+    /// and recreated with the new spec, adopting the running pods & their PVCs. Existing PVCs
+    /// are kept as is, so volumeClaimTemplates changes only apply to new replicas.
+    /// Selector changes & storage shrinks still migrate, as the pods & PVCs can't be adopted.
+    /// serviceName changes are rejected by the webhook, as adopted pods keep their subdomain.
+    Recreate,
 }
 
 pub fn without_replicas(spec: &StatefulSetSpec) -> StatefulSetSpec {
@@ -48,10 +78,6 @@ pub fn without_replicas(spec: &StatefulSetSpec) -> StatefulSetSpec {
 }
 
 impl GratefulSetPoolSpec {
-    pub fn delta_replicas(&mut self, n: i32) {
-        self.sts_spec.replicas = self.sts_spec.replicas.map(|x| max(0, x + n));
-    }
-
     // Adds initcontainer + configmap references
     pub fn with_lock(&self) -> StatefulSetSpec {
         let mut x = self.sts_spec.clone();
@@ -65,7 +91,7 @@ impl GratefulSetPoolSpec {
                 ]),
                 args: Some(vec![
                     String::from("-c"),
-                    String::from(r#"echo ${HOSTNAME} | sed -r 's/.*-([0-9])+$/\1/' | xargs -n 1 -I {} -- [ -e {} ] && echo successfully acquired lock at $$(date -u) || (echo failure && exit 1)"#),
+                    String::from(r#"echo ${HOSTNAME} | sed -r 's/.*-([0-9]+)$/\1/' | xargs -n 1 -I {} -- [ -e {} ] && echo successfully acquired lock at $$(date -u) || (echo failure && exit 1)"#),
                 ]),
                 working_dir: Some(self.lock_dir()),
                 volume_mounts: Some(vec![VolumeMount {
@@ -78,8 +104,9 @@ impl GratefulSetPoolSpec {
 
             let mut vols = p.volumes.unwrap_or_default();
             vols.push(Volume {
+                name: self.lock_volume(),
                 config_map: Some(ConfigMapVolumeSource {
-                    name: Some(self.lock_volume()),
+                    name: Some(self.configmap_name()),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -93,7 +120,7 @@ impl GratefulSetPoolSpec {
         x
     }
 
-    fn configmap_name(&self) -> String {
+    pub fn configmap_name(&self) -> String {
         format!("{}-lock", self.name)
     }
 
@@ -105,284 +132,1231 @@ impl GratefulSetPoolSpec {
         format!("{}-locks", "pikach.us")
     }
 
+    // hash of the immutable fields, identifying the pool.
+    pub fn pool_hash(&self) -> String {
+        ImmutableSts(&self.sts_spec, &self.immutable_fields).hash(0)
+    }
+
+    // Whether both specs share their immutable fields, i.e. belong in the same pool.
+    pub fn same_pool(&self, other: &GratefulSetPoolSpec) -> bool {
+        ImmutableSts(&self.sts_spec, &self.immutable_fields).value()
+            == ImmutableSts(&other.sts_spec, &other.immutable_fields).value()
+    }
+
+    // Whether a pool of this spec can be updated in place to `other`: both share their
+    // immutable fields, save claim storage requests which `other` may grow (see `volumes`).
+    pub fn adopts(&self, other: &GratefulSetPoolSpec) -> bool {
+        ImmutableSts(&self.sts_spec, &self.immutable_fields).sans_storage()
+            == ImmutableSts(&other.sts_spec, &other.immutable_fields).sans_storage()
+            && !volumes::shrinks(&other.sts_spec, &self.sts_spec)
+    }
+
+    // hashes the pool configuration (sans replicas). Used to determine whether
+    // a ScaleDown has already been run for a replica with this configuration.
+    pub fn config_hash(&self) -> String {
+        let mut buf = String::new();
+        canonical_json(
+            &serde_json::to_value(without_replicas(&self.sts_spec)).unwrap_or_default(),
+            &mut buf,
+        );
+        format!("{:x}", fnv1a(buf.as_bytes()))
+    }
+}
+
+pub fn lock_data(replicas: i32) -> BTreeMap<String, String> {
+    let data = (0..replicas).map(|x| (x.to_string(), x.to_string()));
+    BTreeMap::from_iter(data)
+}
+
+// returns the sorted ordinals of the locks in a locks configmap.
+pub fn held_locks(data: &BTreeMap<String, String>) -> Vec<i32> {
+    let mut locks: Vec<i32> = data.keys().filter_map(|k| k.parse().ok()).collect();
+    locks.sort_unstable();
+    locks
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GratefulSetPoolStatus {
+    /// The observed status of the underlying statefulset.
+    #[serde(default, alias = "sts_status")]
+    pub sts_status: StatefulSetStatus,
+    /// Ordinals of the locks currently held in the locks configmap.
+    #[serde(default)]
+    pub locks: Vec<i32>,
+    /// Ordinals of the pods which are ready.
+    #[serde(default)]
+    pub ready_ordinals: Vec<i32>,
+    /// observedGeneration is the most recent generation observed by the controller.
+    #[serde(alias = "observed_generation")]
+    pub observed_generation: Option<i64>,
+    /// ordinal -> config hash for each replica which has had its ScaleDown run.
+    #[serde(default, alias = "scale_down_records")]
+    pub scale_down_records: BTreeMap<i32, String>,
+    /// ordinal -> config hash for each replica which has been confirmed by ScaleUp.
+    #[serde(default, alias = "scale_up_records")]
+    pub scale_up_records: BTreeMap<i32, String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+impl GratefulSetPool {
     // returns the desired configmap with locks for each desired replica.
-    pub fn configmap(&self, ns: String) -> ConfigMap {
+    pub fn configmap(&self) -> ConfigMap {
         ConfigMap {
-            data: Some(lock_data(self.sts_spec.replicas.unwrap_or(1))),
+            data: Some(lock_data(self.spec.sts_spec.replicas.unwrap_or(1))),
             metadata: ObjectMeta {
-                name: Some(self.configmap_name()),
-                namespace: Some(ns),
-                owner_references: Some(vec![OwnerReference {
-                    kind: GratefulSetPool::KIND.to_string(),
-                    ..Default::default()
-                }]),
-                labels: Some(BTreeMap::from_iter(
-                    vec![(String::from("owner.pikach.us"), self.name.clone())].into_iter(),
-                )),
+                name: Some(self.spec.configmap_name()),
+                namespace: Meta::namespace(self),
+                owner_references: Some(vec![controller_ref(self)]),
+                labels: Some(self.child_labels()),
                 ..Default::default()
             },
             ..Default::default()
         }
     }
+
+    // The hash the pool is labeled with. Pools are named by it, so it's kept even when
+    // the hash scheme changes.
+    pub fn hash(&self) -> String {
+        self.metadata
+            .labels
+            .as_ref()
+            .and_then(|x| x.get(labels::POOL_HASH).cloned())
+            .unwrap_or_else(|| self.spec.pool_hash())
+    }
+
+    // The ownership labels of the pool, propagated to its sts, configmap & pods.
+    pub fn child_labels(&self) -> BTreeMap<String, String> {
+        let mut child_labels = self.metadata.labels.clone().unwrap_or_default();
+        child_labels.retain(|k, _| k == labels::OWNER || k == labels::POOL_HASH);
+        child_labels.insert(String::from(labels::POOL_HASH), self.hash());
+        child_labels
+    }
+
+    // The desired sts spec: the spec with locks, whose pods carry the pool's labels.
+    pub fn sts_spec(&self) -> StatefulSetSpec {
+        let mut x = self.spec.with_lock();
+        let mut template_md = x.template.metadata.unwrap_or_default();
+        let mut template_labels = template_md.labels.unwrap_or_default();
+        template_labels.extend(self.child_labels());
+        template_md.labels = Some(template_labels);
+        x.template.metadata = Some(template_md);
+        x
+    }
+
+    /// Ready replicas, only counting the replicas which have also been confirmed
+    /// by the ScaleUp implementation for the current config.
+    pub fn ready_replicas(&self) -> i32 {
+        let status = self.status.clone().unwrap_or_default();
+        let config_hash = self.spec.config_hash();
+        let confirmed = (0..)
+            .take_while(|i| status.scale_up_records.get(i) == Some(&config_hash))
+            .count() as i32;
+        min(status.sts_status.ready_replicas.unwrap_or(0), confirmed)
+    }
+
+    /// Unready replicas which are known to be the highest ordinals, i.e. the first to go when
+    /// the sts is scaled down. Replicas which are ready but unconfirmed count as unready.
+    pub fn unready_top(&self) -> i32 {
+        let status = self.status.clone().unwrap_or_default();
+        let config_hash = self.spec.config_hash();
+        let ready = |o: &i32| {
+            status.ready_ordinals.contains(o)
+                && status.scale_up_records.get(o) == Some(&config_hash)
+        };
+        let replicas = status.sts_status.replicas;
+        let top = (0..replicas).rev().take_while(|o| !ready(o)).count() as i32;
+        // Mirrored ready ordinals can lag behind the sts status: there can't be more of them
+        // than unready replicas.
+        min(top, replicas - self.ready_replicas())
+    }
 }
 
-pub fn lock_data(replicas: i32) -> BTreeMap<String, String> {
-    let data = (0..replicas)
-        .into_iter()
-        .map(|x| (x.to_string(), x.to_string()));
-    BTreeMap::from_iter(data)
+// ready replicas which are also running the current sts revision.
+pub(crate) fn settled_ready(sts: &StatefulSet) -> i32 {
+    let status = sts.status.clone().unwrap_or_default();
+    min(
+        status.current_replicas.unwrap_or(0),
+        status.ready_replicas.unwrap_or(0),
+    )
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
-pub struct GratefulSetPoolStatus {
-    pub sts_status: StatefulSetStatus,
-    pub scale_down_records: BTreeMap<i32, String>,
+// The pool status mirroring the observed sts, locks & pods, keeping the hook records.
+fn observed_status(
+    gsp: &GratefulSetPool,
+    found: &StatefulSet,
+    locks: Option<&ConfigMap>,
+    pods: &[Pod],
+) -> GratefulSetPoolStatus {
+    GratefulSetPoolStatus {
+        sts_status: found.status.clone().unwrap_or_default(),
+        locks: locks
+            .map(|x| held_locks(&x.data.clone().unwrap_or_default()))
+            .unwrap_or_default(),
+        ready_ordinals: ready_ordinals(&Meta::name(gsp), pods),
+        observed_generation: gsp.metadata.generation,
+        conditions: observed_conditions(gsp, found),
+        ..gsp.status.clone().unwrap_or_default()
+    }
 }
 
-pub struct ImmutableSts<'a>(pub &'a StatefulSetSpec);
-
-impl<'a> Hash for ImmutableSts<'a> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.pod_management_policy.hash(state);
-        self.0.revision_history_limit.hash(state);
-        if let Some(xs) = &self.0.selector.match_expressions {
-            for x in xs {
-                x.key.hash(state);
-                x.operator.hash(state);
-                x.values.hash(state);
+// The sorted ordinals of the ready pods among `pods`, named <pool>-<ordinal>.
+fn ready_ordinals(pool: &str, pods: &[Pod]) -> Vec<i32> {
+    let prefix = format!("{}-", pool);
+    let mut ordinals: Vec<i32> = pods
+        .iter()
+        .filter(|p| {
+            let conds = p.status.as_ref().and_then(|s| s.conditions.as_ref());
+            conds
+                .into_iter()
+                .flatten()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+        .filter_map(|p| Meta::name(p).strip_prefix(&prefix)?.parse().ok())
+        .collect();
+    ordinals.sort_unstable();
+    ordinals
+}
+
+// Derives the pool conditions from the observed sts, carrying over the HookFailed state.
+fn observed_conditions(gsp: &GratefulSetPool, found: &StatefulSet) -> Vec<Condition> {
+    let mut conds = gsp.status.clone().unwrap_or_default().conditions;
+    let desired = gsp.spec.sts_spec.replicas.unwrap_or(1);
+    let spec_replicas = found.spec.as_ref().and_then(|x| x.replicas).unwrap_or(1);
+    let status = found.status.clone().unwrap_or_default();
+    let ready = status.ready_replicas.unwrap_or(0);
+    let rolling = SpecDiff::new(&gsp.sts_spec(), &found.spec.clone().unwrap_or_default()).rolls()
+        || status.current_revision != status.update_revision;
+
+    set_condition(
+        &mut conds,
+        if ready >= desired {
+            Condition::new(
+                conditions::AVAILABLE,
+                true,
+                "MinimumReplicasAvailable",
+                format!("{}/{} replicas ready", ready, desired),
+            )
+        } else {
+            Condition::new(
+                conditions::AVAILABLE,
+                false,
+                "MinimumReplicasUnavailable",
+                format!("{}/{} replicas ready", ready, desired),
+            )
+        },
+    );
+
+    set_condition(
+        &mut conds,
+        if rolling {
+            Condition::new(
+                conditions::PROGRESSING,
+                true,
+                "RollingUpdate",
+                "rolling the statefulset to the desired spec",
+            )
+        } else if desired != spec_replicas {
+            Condition::new(
+                conditions::PROGRESSING,
+                true,
+                "Scaling",
+                format!("scaling from {} to {} replicas", spec_replicas, desired),
+            )
+        } else {
+            Condition::new(
+                conditions::PROGRESSING,
+                false,
+                "RolloutComplete",
+                "statefulset matches the desired spec",
+            )
+        },
+    );
+
+    set_condition(
+        &mut conds,
+        if desired < spec_replicas {
+            Condition::new(
+                conditions::SCALING_DOWN,
+                true,
+                "RetiringReplica",
+                format!("retiring replica {}-{}", Meta::name(gsp), spec_replicas - 1),
+            )
+        } else {
+            Condition::new(conditions::SCALING_DOWN, false, "NoReplicaRetiring", "")
+        },
+    );
+
+    let expected_ready = min(desired, spec_replicas);
+    set_condition(
+        &mut conds,
+        if !rolling && ready < expected_ready {
+            Condition::new(
+                conditions::DEGRADED,
+                true,
+                "ReplicasUnready",
+                format!("{}/{} replicas ready", ready, expected_ready),
+            )
+        } else {
+            Condition::new(conditions::DEGRADED, false, "ReplicasReady", "")
+        },
+    );
+
+    if conditions::find(&conds, conditions::HOOK_FAILED).is_none() {
+        set_condition(
+            &mut conds,
+            Condition::new(conditions::HOOK_FAILED, false, "NoHookFailures", ""),
+        );
+    }
+    conds
+}
+
+// Reflects the outcome of a ScaleUp/ScaleDown hook in the HookFailed condition,
+// passing the result through.
+async fn report_hook<T>(
+    pools: &Api<GratefulSetPool>,
+    gsp: &GratefulSetPool,
+    hook: &str,
+    ordinal: i32,
+    res: Result<T>,
+) -> Result<T> {
+    let mut conds = gsp.status.clone().unwrap_or_default().conditions;
+    let was_failed = conditions::is_true(&conds, conditions::HOOK_FAILED);
+    let cond = match &res {
+        Ok(_) => Condition::new(
+            conditions::HOOK_FAILED,
+            false,
+            &format!("{}Succeeded", hook),
+            format!("{} succeeded for replica {}", hook, ordinal),
+        ),
+        Err(e) => Condition::new(
+            conditions::HOOK_FAILED,
+            true,
+            &format!("{}Failed", hook),
+            format!("{} failed for replica {}: {}", hook, ordinal, e),
+        ),
+    };
+
+    if res.is_err() || was_failed {
+        set_condition(&mut conds, cond);
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "status": { "conditions": conds }
+        }))?;
+        pools
+            .patch_status(&Meta::name(gsp), &PatchParams::default(), patch)
+            .await
+            .map_err(|e| Error::with_chain(e, "updating gratefulsetpool conditions"))?;
+    }
+    res
+}
+
+// Runs the ScaleUp implementation for each ready replica which hasn't yet been confirmed
+// with the current config, recording confirmations in the pool status.
+// Returns whether every replica of the sts has been confirmed.
+async fn confirm_scale_up(
+    gsp: &GratefulSetPool,
+    pools: &Api<GratefulSetPool>,
+    hooks: &Hooks,
+    found: &StatefulSet,
+) -> Result<bool> {
+    let replicas = found.spec.as_ref().and_then(|x| x.replicas).unwrap_or(1);
+    let ready = settled_ready(found);
+    let config_hash = gsp.spec.config_hash();
+    let records = gsp
+        .status
+        .clone()
+        .map(|s| s.scale_up_records)
+        .unwrap_or_default();
+
+    let mut confirmed = BTreeMap::new();
+    let mut all = true;
+    for ordinal in 0..replicas {
+        if records.get(&ordinal) == Some(&config_hash) {
+            continue;
+        }
+        if ordinal >= ready {
+            all = false;
+            break;
+        }
+        let res = hooks.scale_up.scale_up(gsp, ordinal).await;
+        if !report_hook(pools, gsp, "ScaleUp", ordinal, res)
+            .await
+            .chain_err(|| format!("running ScaleUp for {}-{}", Meta::name(gsp), ordinal))?
+        {
+            all = false;
+            break;
+        }
+        confirmed.insert(ordinal.to_string(), config_hash.clone());
+    }
+
+    if !confirmed.is_empty() {
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "status": { "scaleUpRecords": confirmed }
+        }))?;
+        pools
+            .patch_status(&Meta::name(gsp), &PatchParams::default(), patch)
+            .await
+            .map_err(|e| {
+                Error::with_chain(
+                    e,
+                    format!("recording ScaleUp confirmations for {}", Meta::name(gsp)),
+                )
+            })?;
+    }
+    Ok(all)
+}
+
+// Whether a replica's pod is running its containers, i.e. has something for ScaleDown to shut
+// down. A missing pod, or one held in its init container by a revoked lock, has nothing left
+// running: waiting on the hook for it would stall the retirement for good.
+fn is_running(pod: Option<&Pod>) -> bool {
+    pod.and_then(|p| p.status.as_ref()?.phase.as_deref()) == Some("Running")
+}
+
+// Number of hex characters of the pool hash.
+const HASH_LEN: usize = 10;
+
+/// ImmutableSts is the subset of a StatefulSetSpec which can't be updated in place,
+/// identifying a pool.
+///
+/// Following the statefulset update validation, that's every field save replicas & the
+/// `diff::MUTABLE` ones, including all of the volumeClaimTemplates (sans status). The storage
+/// they request is part of the pool hash, so a shrink gets a new pool, but growing it is done in
+/// place (see `sans_storage` & `volumes`).
+/// Fields are compared after defaulting, so e.g. an unset podManagementPolicy is the same
+/// pool as an explicit `OrderedReady`.
+///
+/// The second field lists paths of additional (otherwise mutable) fields to treat as immutable,
+/// so changing them migrates to a new pool. Unset paths are recorded as null, so setting them
+/// later migrates too.
+pub struct ImmutableSts<'a>(pub &'a StatefulSetSpec, pub &'a [String]);
+
+impl<'a> ImmutableSts<'a> {
+    // The immutable fields as JSON.
+    pub fn value(&self) -> Value {
+        let spec = serde_json::to_value(defaults::sts_spec(self.0)).unwrap_or_default();
+        let mut x = spec.clone();
+        if let Some(fields) = x.as_object_mut() {
+            fields.remove("replicas");
+            for f in MUTABLE {
+                fields.remove(*f);
+            }
+            // Left out when unused so the hashes of existing pools don't change.
+            if !self.1.is_empty() {
+                let extra: serde_json::Map<String, Value> = self
+                    .1
+                    .iter()
+                    .map(|path| {
+                        (
+                            path.clone(),
+                            diff::lookup(&spec, path).cloned().unwrap_or(Value::Null),
+                        )
+                    })
+                    .collect();
+                fields.insert(String::from("immutableFields"), Value::Object(extra));
             }
         }
-        self.0.selector.match_labels.hash(state);
-        self.0.service_name.hash(state);
-        if let Some(xs) = &self.0.volume_claim_templates {
-            for x in xs {
-                x.metadata.annotations.hash(state);
-                x.metadata.cluster_name.hash(state);
-                x.metadata.deletion_grace_period_seconds.hash(state);
-                x.metadata.finalizers.hash(state);
-                x.metadata.generate_name.hash(state);
-                x.metadata.labels.hash(state);
-                x.metadata.name.hash(state);
-                x.metadata.namespace.hash(state);
-
-                if let Some(spec) = &x.spec {
-                    spec.access_modes.hash(state);
-                    if let Some(r) = &spec.resources {
-                        if let Some(l) = &r.limits {
-                            for (k, v) in l {
-                                k.hash(state);
-                                v.0.as_str().hash(state);
-                            }
-                        }
-                        if let Some(reqs) = &r.limits {
-                            for (k, v) in reqs {
-                                k.hash(state);
-                                v.0.as_str().hash(state);
-                            }
-                        }
-                    }
+        x
+    }
 
-                    if let Some(ls) = &spec.selector {
-                        if let Some(xs) = &ls.match_expressions {
-                            for x in xs {
-                                x.key.hash(state);
-                                x.operator.hash(state);
-                                x.values.hash(state);
-                            }
-                        }
-                        ls.match_labels.hash(state);
-                    }
+    // The immutable fields as JSON, save the storage requested by the volumeClaimTemplates.
+    // Specs with equal values only differ by storage, which may be grown in place.
+    pub fn sans_storage(&self) -> Value {
+        let mut x = self.value();
+        let claims = x
+            .get_mut("volumeClaimTemplates")
+            .and_then(|x| x.as_array_mut());
+        for claim in claims.into_iter().flatten() {
+            if let Some(requests) = claim
+                .pointer_mut("/spec/resources/requests")
+                .and_then(|x| x.as_object_mut())
+            {
+                requests.remove("storage");
+            }
+        }
+        x
+    }
 
-                    spec.storage_class_name.hash(state);
-                    spec.volume_mode.hash(state);
-                    spec.volume_name.hash(state);
+    /// A stable hash of the immutable fields: FNV-1a over their canonical JSON.
+    /// A non zero `collision_count` salts the hash, picking a new name when two
+    /// different specs hash alike.
+    pub fn hash(&self, collision_count: i32) -> String {
+        let mut buf = String::new();
+        canonical_json(&self.value(), &mut buf);
+        if collision_count > 0 {
+            buf.push_str(&collision_count.to_string());
+        }
+        let mut hex = format!("{:016x}", fnv1a(buf.as_bytes()));
+        hex.truncate(HASH_LEN);
+        hex
+    }
+}
+
+// Writes `v` as JSON with object keys sorted, so equal values always serialize alike.
+fn canonical_json(v: &Value, out: &mut String) {
+    match v {
+        Value::Object(m) => {
+            let mut keys: Vec<&String> = m.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, k) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(k.clone()).to_string());
+                out.push(':');
+                canonical_json(&m[k], out);
+            }
+            out.push('}');
+        }
+        Value::Array(xs) => {
+            out.push('[');
+            for (i, x) in xs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
                 }
+                canonical_json(x, out);
             }
+            out.push(']');
         }
+        x => out.push_str(&x.to_string()),
     }
 }
 
-impl<'a> ImmutableSts<'a> {
-    // discard all but 16 bits. We'll hex them into 8 characters.
-    pub fn checksum(&self) -> u16 {
-        let mut s = std::collections::hash_map::DefaultHasher::new();
-        self.hash(&mut s);
-        s.finish() as u16
+// 64 bit FNV-1a. Unlike std's DefaultHasher it's specified, so hashes are stable across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Deletes the sts, leaving its pods running. It's recreated by the next reconcile,
+// adopting them.
+async fn orphan(sts: &Api<StatefulSet>, name: &str) -> Result<()> {
+    info!("recreating statefulset {}, orphaning its pods", name);
+    let dp = DeleteParams {
+        propagation_policy: Some(PropagationPolicy::Orphan),
+        ..Default::default()
+    };
+    match sts.delete(name, &dp).await {
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        res => res
+            .map(|_| ())
+            .map_err(|e| Error::with_chain(e, "deleting statefulset")),
     }
 }
 
-async fn reconcile(gsp: GratefulSetPool, ctx: Context<Data>) -> Result<ReconcilerAction> {
+// Sets the replicas of the pool's sts, updating it to the desired spec.
+async fn patch_sts(
+    sts: &Api<StatefulSet>,
+    gsp: &GratefulSetPool,
+    replicas: Option<i32>,
+) -> Result<()> {
+    let patch = serde_yaml::to_vec(&serde_json::json!({ "spec": StatefulSetSpec {
+        replicas,
+        ..gsp.sts_spec()
+    } }))?;
+    sts.patch(
+        &Meta::name(gsp),
+        &PatchParams::apply("gratefulsetpool-mgr"),
+        patch,
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| Error::with_chain(e, "updating statefulset"))
+}
+
+// Sets the locks configmap to hold the locks of replicas `0..replicas`.
+async fn patch_locks(
+    configmaps: &Api<ConfigMap>,
+    gsp: &GratefulSetPool,
+    replicas: i32,
+) -> Result<()> {
+    let mut locks = gsp.configmap();
+    locks.data = Some(lock_data(replicas));
+    let cm_patch = serde_yaml::to_vec(&serde_json::json!(locks))?;
+    configmaps
+        .patch(
+            &Meta::name(&locks),
+            &PatchParams::apply("gratefulsetpool-mgr"),
+            cm_patch,
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| Error::with_chain(e, "updating locks configmap"))
+}
+
+pub(crate) async fn reconcile(
+    gsp: GratefulSetPool,
+    ctx: Context<Data>,
+) -> Result<ReconcilerAction> {
     let client = ctx.get_ref().client.clone();
     let name = Meta::name(&gsp);
     let ns = Meta::namespace(&gsp).expect("gs is namespaced");
     debug!("Reconcile GratefulSetPool {}: {:?}", name, gsp);
 
-    let finished = Ok(ReconcilerAction {
-        // try again in 5min
-        requeue_after: None,
+    let sts: Api<StatefulSet> = Api::namespaced(client.clone(), &ns);
+    let pools: Api<GratefulSetPool> = Api::namespaced(client.clone(), &ns);
+    let configmaps: Api<ConfigMap> = Api::namespaced(client.clone(), &ns);
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
+    let hooks = &ctx.get_ref().hooks;
+    let requeue = Ok(ReconcilerAction {
+        requeue_after: Some(Duration::from_secs(10)),
     });
 
-    let sts: Api<StatefulSet> = Api::namespaced(client.clone(), &ns);
+    let found = match sts.get(&name).await {
+        Ok(found) => Some(found),
+        Err(kube::Error::Api(e)) if e.code == 404 => None,
+        Err(e) => return Err(e.into()),
+    };
+    let locks = match configmaps.get(&gsp.spec.configmap_name()).await {
+        Ok(locks) => Some(locks),
+        Err(kube::Error::Api(e)) if e.code == 404 => None,
+        Err(e) => return Err(e.into()),
+    };
 
-    // TODO: replace with 404 matching when we know which error type this is.
-    let found = sts.get(&Meta::name(&gsp)).await?;
-
-    // If the specs are equal, this is a noop.
-    if gsp.spec.with_lock() == found.spec.clone().unwrap_or_default() {
-        return finished;
-    }
-
-    // The spec has changed. Update it & start the underlying rollout (sans replicas).
-    let desired_sans_replicas = without_replicas(&gsp.spec.with_lock());
-    if without_replicas(&found.clone().spec.unwrap_or_default()) != desired_sans_replicas {
-        let prev_replicas = found.clone().spec.clone().and_then(|x| x.replicas);
-        let patch = serde_yaml::to_vec(&serde_json::json!({ "spec": StatefulSetSpec {
-            replicas: prev_replicas,
-            ..desired_sans_replicas.clone()
-        } }))?;
-        return sts
-            .patch(
-                &Meta::name(&gsp),
-                &PatchParams::apply("gratefulsetpool-mgr"),
-                patch,
-            )
-            .await
-            .map_err(|e| Error::with_chain(e, "something went wrong"))
-            .and(finished);
-    }
-
-    // From this point on, we know the desired spec (sans replicas) exists on the underlying sts
-    // and therefore the only difference is the number of specified replicas.
-    // Thus we have two options: scaling down and scaling up.
-    // Scaling up is expected to be the simple path, but may change in the future. For now, we'll update the underlying configmap to include the lockfile for our new replica and increase the sts replicas to the desired number. It's foreseeable that in the future we may include custom scale-up strategies.
-    // Scaling down is a different story. We do the following in order:
-    // 1) Update underlying locks configmap to (cur-1) replicas
-    // 2) Run ScaleDown implementation (http call, etc)
-    // 3) Wait for new replica count to settle.
-
-    let desired_replicas: i32 = gsp.spec.sts_spec.replicas.unwrap_or(1);
-    let found_spec_replicas: i32 = found.spec.as_ref().and_then(|x| x.replicas).unwrap_or(1);
-    let found_current_replicas: i32 = found
-        .status
-        .as_ref()
-        .and_then(|x| x.current_replicas)
-        .unwrap_or(0);
-    let found_ready: i32 = min(
-        found
-            .status
-            .as_ref()
-            .and_then(|x| x.current_replicas)
-            .unwrap_or(0),
-        found
-            .status
-            .as_ref()
-            .and_then(|x| x.ready_replicas)
-            .unwrap_or(0),
-    );
+    // Mirror the observed sts, locks & pods into the pool status so the parent GratefulSet
+    // makes its rollout decisions on up to date information.
+    let gsp = match &found {
+        Some(found) => {
+            let selector = gsp
+                .child_labels()
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(",");
+            let found_pods = pods
+                .list(&ListParams::default().labels(&selector))
+                .await
+                .map_err(|e| Error::with_chain(e, format!("listing pods of {}", name)))?
+                .items;
+            let observed = observed_status(&gsp, found, locks.as_ref(), &found_pods);
+            if gsp.status.as_ref() != Some(&observed) {
+                let patch = serde_json::to_vec(&serde_json::json!({
+                    "status": {
+                        "stsStatus": observed.sts_status,
+                        "locks": observed.locks,
+                        "readyOrdinals": observed.ready_ordinals,
+                        "observedGeneration": observed.observed_generation,
+                        "conditions": observed.conditions,
+                    }
+                }))?;
+                pools
+                    .patch_status(&name, &PatchParams::default(), patch)
+                    .await
+                    .map_err(|e| Error::with_chain(e, "updating gratefulsetpool status"))?
+            } else {
+                gsp
+            }
+        }
+        None => gsp,
+    };
 
-    // Bail early if we're still waiting for a spec difference to finish rolling out
-    if found_spec_replicas != found_current_replicas {
-        return finished;
+    if let Some(found) = &found {
+        debug!(
+            "GratefulSetPool {} differs from its statefulset: {}",
+            name,
+            SpecDiff::new(&gsp.sts_spec(), &found.spec.clone().unwrap_or_default())
+        );
+    }
+    let actions = ObservedPool {
+        pool: &gsp,
+        sts: found.as_ref(),
+        locks: locks.as_ref(),
+    }
+    .plan()?;
+    if actions.is_empty() {
+        return Ok(ReconcilerAction {
+            requeue_after: None,
+        });
     }
 
-    let configmaps: Api<ConfigMap> = Api::namespaced(client.clone(), &ns);
+    let status = gsp.status.clone().unwrap_or_default();
+    let config_hash = gsp.spec.config_hash();
+    for action in actions {
+        info!("GratefulSetPool {}: {}", name, action);
+        match action {
+            PoolAction::CreateLocks => {
+                match configmaps
+                    .create(&PostParams::default(), &gsp.configmap())
+                    .await
+                {
+                    Err(kube::Error::Api(e)) if e.code == 409 => {}
+                    res => {
+                        res.map_err(|e| Error::with_chain(e, "creating locks configmap"))?;
+                    }
+                }
+            }
+            PoolAction::CreateStatefulSet { replicas } => {
+                let want = StatefulSet {
+                    metadata: ObjectMeta {
+                        name: Some(name.clone()),
+                        namespace: Some(ns.clone()),
+                        owner_references: Some(vec![controller_ref(&gsp)]),
+                        labels: Some(gsp.child_labels()),
+                        ..Default::default()
+                    },
+                    spec: Some(StatefulSetSpec {
+                        replicas: Some(replicas),
+                        ..gsp.sts_spec()
+                    }),
+                    ..Default::default()
+                };
+                sts.create(&PostParams::default(), &want)
+                    .await
+                    .map_err(|e| Error::with_chain(e, "creating statefulset"))?;
+            }
+            PoolAction::UpdateStatefulSet => {
+                let prev_replicas = found.as_ref().and_then(|x| x.spec.as_ref()?.replicas);
+                patch_sts(&sts, &gsp, prev_replicas).await?;
+            }
+            PoolAction::ExpandVolumes { grown } => {
+                volumes::expand(client.clone(), &gsp, &grown).await?;
+            }
+            PoolAction::RecreateStatefulSet => orphan(&sts, &name).await?,
+            PoolAction::ConfirmScaleUp => {
+                let found = found.as_ref().expect("planned with the statefulset");
+                if !confirm_scale_up(&gsp, &pools, hooks, found).await? {
+                    return requeue;
+                }
+            }
+            PoolAction::ForgetScaleDowns { from } => {
+                let stale: BTreeMap<String, Option<String>> = status
+                    .scale_down_records
+                    .range(from..)
+                    .map(|(k, _)| (k.to_string(), None))
+                    .collect();
+                let patch = serde_json::to_vec(&serde_json::json!({
+                    "status": { "scaleDownRecords": stale }
+                }))?;
+                pools
+                    .patch_status(&name, &PatchParams::default(), patch)
+                    .await
+                    .map_err(|e| Error::with_chain(e, "forgetting ScaleDown records"))?;
+            }
+            PoolAction::AddLock(ordinal) => patch_locks(&configmaps, &gsp, ordinal + 1).await?,
+            PoolAction::RevokeLock(ordinal) => patch_locks(&configmaps, &gsp, ordinal).await?,
+            PoolAction::RunScaleDown(ordinal) => {
+                let pod_name = format!("{}-{}", name, ordinal);
+                let pod = match pods.get(&pod_name).await {
+                    Ok(pod) => Some(pod),
+                    Err(kube::Error::Api(e)) if e.code == 404 => None,
+                    Err(e) => {
+                        return Err(Error::with_chain(e, format!("getting pod {}", pod_name)))
+                    }
+                };
+                if is_running(pod.as_ref()) {
+                    let res = hooks.scale_down.scale_down(&gsp, ordinal).await;
+                    report_hook(&pools, &gsp, "ScaleDown", ordinal, res)
+                        .await
+                        .chain_err(|| format!("running ScaleDown for {}", pod_name))?;
+                } else {
+                    info!("skipping ScaleDown for {}: it isn't running", pod_name);
+                }
 
-    // Scale up
-    if desired_replicas > found_spec_replicas {
-        // ensure locks configmap is updated
-        let locks = gsp.spec.configmap(String::from(&ns));
-        let should_update = configmaps
-            .get(&Meta::name(&locks))
-            .await
-            .and_then(|x| Ok(x.data.unwrap_or_default()))
-            .map(|x| x.get(&desired_replicas.to_string()).is_none())
-            .unwrap_or(true);
-
-        if should_update {
-            let cm_patch = serde_yaml::to_vec(&serde_json::json!(locks))?;
-            configmaps
-                .patch(
-                    &Meta::name(&locks),
-                    &PatchParams::apply("gratefulsetpool-mgr"),
-                    cm_patch,
-                )
-                .await
-                .map_err(|e| Error::with_chain(e, "something went wrong"))?;
-
-            let patch = serde_yaml::to_vec(&serde_json::json!({ "spec": gsp.spec }))?;
-            return sts
-                .patch(
-                    &Meta::name(&gsp),
-                    &PatchParams::apply("gratefulsetpool-mgr"),
-                    patch,
-                )
-                .await
-                .map_err(|e| Error::with_chain(e, "something went wrong"))
-                .and(finished);
+                let patch = serde_json::to_vec(&serde_json::json!({
+                    "status": {
+                        "scaleDownRecords": { ordinal.to_string(): config_hash },
+                        // a retired replica is no longer confirmed by ScaleUp.
+                        "scaleUpRecords": { ordinal.to_string(): null },
+                    }
+                }))?;
+                pools
+                    .patch_status(&name, &PatchParams::default(), patch)
+                    .await
+                    .map_err(|e| Error::with_chain(e, "recording ScaleDown"))?;
+            }
+            PoolAction::ScaleStatefulSet(replicas) => {
+                patch_sts(&sts, &gsp, Some(replicas)).await?;
+            }
+        }
+    }
+    requeue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+    use serde_json::json;
+
+    type Edit = Box<dyn Fn(&mut Value)>;
+
+    fn spec(edit: impl Fn(&mut Value)) -> GratefulSetPoolSpec {
+        GratefulSetPoolSpec {
+            name: String::from("ingester"),
+            sts_spec: fixtures::sts_spec(edit),
+            ..Default::default()
         }
+    }
+
+    #[test]
+    fn immutable_fields() {
+        // (case, whether it yields a new pool, edit to the sts spec)
+        let cases: Vec<(&str, bool, Edit)> = vec![
+            ("unchanged", false, Box::new(|_| {})),
+            ("replicas", false, Box::new(|x| x["replicas"] = json!(5))),
+            (
+                "image",
+                false,
+                Box::new(|x| x["template"]["spec"]["containers"][0]["image"] = json!("loki:2.1.0")),
+            ),
+            (
+                "template labels",
+                false,
+                Box::new(|x| x["template"]["metadata"]["labels"]["tier"] = json!("write")),
+            ),
+            (
+                "updateStrategy",
+                false,
+                Box::new(|x| x["updateStrategy"] = json!({ "type": "OnDelete" })),
+            ),
+            (
+                "revisionHistoryLimit",
+                true,
+                Box::new(|x| x["revisionHistoryLimit"] = json!(3)),
+            ),
+            (
+                "defaulted podManagementPolicy",
+                false,
+                Box::new(|x| x["podManagementPolicy"] = json!("OrderedReady")),
+            ),
+            (
+                "podManagementPolicy",
+                true,
+                Box::new(|x| x["podManagementPolicy"] = json!("Parallel")),
+            ),
+            (
+                "serviceName",
+                true,
+                Box::new(|x| x["serviceName"] = json!("ingester-headless")),
+            ),
+            (
+                "selector",
+                true,
+                Box::new(|x| x["selector"]["matchLabels"]["tier"] = json!("write")),
+            ),
+            // a new pool hash, though growing it is done in place (see `adopts_grown_storage`)
+            (
+                "claim storage requests",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["resources"]["requests"]["storage"] =
+                        json!("20Gi")
+                }),
+            ),
+            (
+                "claim other requests",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["resources"]["requests"]["iops"] =
+                        json!("3000")
+                }),
+            ),
+            (
+                "claim limits",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["resources"]["limits"] =
+                        json!({ "storage": "20Gi" })
+                }),
+            ),
+            (
+                "claim dataSource",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["dataSource"] = json!({
+                        "apiGroup": "snapshot.storage.k8s.io",
+                        "kind": "VolumeSnapshot",
+                        "name": "data-snapshot",
+                    })
+                }),
+            ),
+            (
+                "claim storageClassName",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["storageClassName"] = json!("ssd")
+                }),
+            ),
+            (
+                "defaulted claim volumeMode",
+                false,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["spec"]["volumeMode"] = json!("Filesystem")
+                }),
+            ),
+            (
+                "claim status",
+                false,
+                Box::new(|x| x["volumeClaimTemplates"][0]["status"] = json!({ "phase": "Bound" })),
+            ),
+            (
+                "claim labels",
+                true,
+                Box::new(|x| {
+                    x["volumeClaimTemplates"][0]["metadata"]["labels"] = json!({ "app": "loki" })
+                }),
+            ),
+            (
+                "added claim",
+                true,
+                Box::new(|x| {
+                    let mut wal = x["volumeClaimTemplates"][0].clone();
+                    wal["metadata"]["name"] = json!("wal");
+                    x["volumeClaimTemplates"].as_array_mut().unwrap().push(wal);
+                }),
+            ),
+        ];
 
-        // no update is required
-        return finished;
+        let base = spec(|_| {});
+        for (name, new_pool, edit) in cases {
+            let edited = spec(edit);
+            assert_eq!(
+                base.pool_hash() != edited.pool_hash(),
+                new_pool,
+                "{}: pool hash",
+                name
+            );
+            assert_eq!(!base.same_pool(&edited), new_pool, "{}: same_pool", name);
+        }
     }
 
-    // Scale down
-    // TODO: scale down hooks
-    if desired_replicas < found_spec_replicas {
-        let mut locks = gsp.spec.configmap(String::from(&ns));
-        let should_update = configmaps
-            .get(&Meta::name(&locks))
-            .await
-            .and_then(|x| Ok(x.data.unwrap_or_default()))
-            // if the lock for found (which is greater than desired) still exists, we need to remove it
-            // and begin decrementing locks.
-            .map(|x| x.get(&(found_spec_replicas).to_string()).is_some())
-            .unwrap_or(true);
-
-        if should_update {
-            // ensure lock is removed for the nth replica
-            // decrement locks configmap to prevent underlying sts replica from restarting.
-            locks.data = Some(lock_data(found_spec_replicas - 1));
-            let cm_patch = serde_yaml::to_vec(&serde_json::json!(locks))?;
-            configmaps
-                .patch(
-                    &Meta::name(&locks),
-                    &PatchParams::apply("gratefulsetpool-mgr"),
-                    cm_patch,
-                )
-                .await
-                .map_err(|e| Error::with_chain(e, "something went wrong"))?;
-
-            // ensure that if unready replicas exist, we can decrement the spec now, too.
-            if found
-                .status
-                .and_then(|s| s.ready_replicas)
-                .map(|x| x < found_spec_replicas)
-                .unwrap_or(false)
-            {
-                let patch = serde_yaml::to_vec(
-                    &serde_json::json!({ "spec": gsp.spec.clone().delta_replicas(-1) }),
-                )?;
-                return sts
-                    .patch(
-                        &Meta::name(&gsp),
-                        &PatchParams::apply("gratefulsetpool-mgr"),
-                        patch,
-                    )
-                    .await
-                    .map_err(|e| Error::with_chain(e, "something went wrong"))
-                    .and(finished);
+    #[test]
+    fn extra_immutable_fields() {
+        let with_image = |image: &str, tier: &str| GratefulSetPoolSpec {
+            immutable_fields: vec![String::from("template.spec.containers[0].image")],
+            ..spec(|x| {
+                x["template"]["spec"]["containers"][0]["image"] = json!(image);
+                x["template"]["metadata"]["labels"]["tier"] = json!(tier);
+            })
+        };
+        let base = with_image("loki:2.0.0", "write");
+        assert!(!base.same_pool(&with_image("loki:2.1.0", "write")));
+        assert!(base.same_pool(&with_image("loki:2.0.0", "read")));
+        assert_ne!(
+            base.pool_hash(),
+            with_image("loki:2.1.0", "write").pool_hash()
+        );
+
+        // an unset path is immutable too: adding the sidecar it points to migrates.
+        let sidecar = |x: &mut Value| {
+            let containers = x["template"]["spec"]["containers"].as_array_mut().unwrap();
+            containers.push(json!({ "name": "sidecar", "image": "a" }));
+        };
+        let unset = GratefulSetPoolSpec {
+            immutable_fields: vec![String::from("template.spec.containers[1].image")],
+            ..spec(|_| {})
+        };
+        assert!(!unset.same_pool(&GratefulSetPoolSpec {
+            immutable_fields: unset.immutable_fields.clone(),
+            ..spec(sidecar)
+        }));
+    }
+
+    #[test]
+    fn adopts_grown_storage() {
+        let storage = |size: &str| {
+            let size = json!(size);
+            spec(move |x| {
+                x["volumeClaimTemplates"][0]["spec"]["resources"]["requests"]["storage"] =
+                    size.clone()
+            })
+        };
+        let base = storage("10Gi");
+        assert!(base.adopts(&base));
+        assert!(base.adopts(&storage("20Gi")));
+        assert!(!base.adopts(&storage("5Gi")));
+        assert!(!base.adopts(&spec(|x| x["serviceName"] = json!("other"))));
+    }
+
+    #[test]
+    fn locks_the_desired_replicas() {
+        let pool = fixtures::gratefulset(|_| {}).pool();
+        let cm = pool.configmap();
+        assert_eq!(
+            cm.metadata.name,
+            Some(format!("{}-lock", Meta::name(&pool)))
+        );
+        assert_eq!(cm.metadata.namespace.as_deref(), Some("loki"));
+        assert_eq!(held_locks(&cm.data.unwrap()), vec![0, 1, 2]);
+
+        // each pod waits for its lock in an init container mounting the configmap.
+        let pod = pool.sts_spec().template.spec.unwrap();
+        let init = &pod.init_containers.unwrap()[0];
+        let mount = &init.volume_mounts.as_ref().unwrap()[0];
+        let volume = pod
+            .volumes
+            .unwrap()
+            .into_iter()
+            .find(|v| v.name == mount.name)
+            .unwrap();
+        assert_eq!(
+            volume.config_map.and_then(|x| x.name),
+            Some(format!("{}-lock", Meta::name(&pool)))
+        );
+    }
+
+    #[test]
+    fn children_are_controlled_by_their_owner() {
+        let mut gs = fixtures::gratefulset(|_| {});
+        gs.metadata.uid = Some(String::from("gs-uid"));
+        let mut pool = gs.pool();
+        pool.metadata.uid = Some(String::from("pool-uid"));
+
+        let owner = |kind: &str, name: &str, uid: &str| OwnerReference {
+            api_version: String::from("pikach.us/v1"),
+            kind: String::from(kind),
+            name: String::from(name),
+            uid: String::from(uid),
+            controller: Some(true),
+            block_owner_deletion: Some(true),
+        };
+        assert_eq!(
+            pool.metadata.owner_references,
+            Some(vec![owner("GratefulSet", "ingester", "gs-uid")])
+        );
+        assert_eq!(
+            pool.configmap().metadata.owner_references,
+            Some(vec![owner(
+                "GratefulSetPool",
+                &Meta::name(&pool),
+                "pool-uid"
+            )])
+        );
+    }
+
+    #[test]
+    fn children_carry_the_pool_labels() {
+        let mut pool = fixtures::gratefulset(|_| {}).pool();
+        assert_eq!(
+            pool.metadata.labels,
+            Some(labels::pool_labels("ingester", &pool.hash()))
+        );
+
+        // labels added to the pool by others aren't propagated.
+        pool.metadata
+            .labels
+            .as_mut()
+            .unwrap()
+            .insert(String::from("team"), String::from("loki"));
+        let child_labels = pool.child_labels();
+        assert_eq!(child_labels, labels::pool_labels("ingester", &pool.hash()));
+        assert_eq!(pool.configmap().metadata.labels, Some(child_labels.clone()));
+
+        // pods carry them on top of the template's own labels.
+        let pod_labels = pool.sts_spec().template.metadata.unwrap().labels.unwrap();
+        assert_eq!(pod_labels["app"], "ingester");
+        assert!(child_labels
+            .iter()
+            .all(|(k, v)| pod_labels.get(k) == Some(v)));
+    }
+
+    #[test]
+    fn mirrors_the_observed_statefulset() {
+        let mut pool = fixtures::found_pool(&fixtures::gratefulset(|_| {}), 3, 0);
+        pool.metadata.generation = Some(4);
+        let records = pool.status.clone().unwrap().scale_up_records;
+        let found = StatefulSet {
+            spec: Some(pool.sts_spec()),
+            status: Some(StatefulSetStatus {
+                replicas: 3,
+                ready_replicas: Some(2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let locks = ConfigMap {
+            data: Some(lock_data(2)),
+            ..Default::default()
+        };
+
+        let observed = observed_status(&pool, &found, Some(&locks), &[]);
+        assert_eq!(Some(&observed.sts_status), found.status.as_ref());
+        assert_eq!(observed.locks, vec![0, 1]);
+        assert_eq!(observed.observed_generation, Some(4));
+        assert_eq!(observed.scale_up_records, records);
+        assert!(!conditions::is_true(
+            &observed.conditions,
+            conditions::AVAILABLE
+        ));
+        assert!(observed_status(&pool, &found, None, &[]).locks.is_empty());
+
+        // once mirrored, there's nothing left to patch.
+        pool.status = Some(observed.clone());
+        assert_eq!(observed_status(&pool, &found, Some(&locks), &[]), observed);
+    }
+
+    #[test]
+    fn observes_conditions() {
+        let pool = fixtures::gratefulset(|_| {}).pool();
+        // A sts of `replicas` with `ready` ready replicas, edited after the pool's spec.
+        let sts = |replicas: i32, ready: i32, edit: &dyn Fn(&mut StatefulSet)| {
+            let mut found = StatefulSet {
+                spec: Some(StatefulSetSpec {
+                    replicas: Some(replicas),
+                    ..pool.sts_spec()
+                }),
+                status: Some(StatefulSetStatus {
+                    replicas,
+                    ready_replicas: Some(ready),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            edit(&mut found);
+            found
+        };
+        let rolled = |_: &mut StatefulSet| {};
+        let outdated = |x: &mut StatefulSet| {
+            let pod = x.spec.as_mut().unwrap().template.spec.as_mut().unwrap();
+            pod.containers[0].image = Some(String::from("loki:1.6.0"));
+        };
+        let revising = |x: &mut StatefulSet| {
+            x.status.as_mut().unwrap().update_revision = Some(String::from("next"));
+        };
+        // (case, found sts, expected (type, status, reason) of each condition)
+        let cases = vec![
+            (
+                "rolled out",
+                sts(3, 3, &rolled),
+                vec![
+                    (conditions::AVAILABLE, true, "MinimumReplicasAvailable"),
+                    (conditions::PROGRESSING, false, "RolloutComplete"),
+                    (conditions::SCALING_DOWN, false, "NoReplicaRetiring"),
+                    (conditions::DEGRADED, false, "ReplicasReady"),
+                    (conditions::HOOK_FAILED, false, "NoHookFailures"),
+                ],
+            ),
+            (
+                "unready",
+                sts(3, 2, &rolled),
+                vec![
+                    (conditions::AVAILABLE, false, "MinimumReplicasUnavailable"),
+                    (conditions::PROGRESSING, false, "RolloutComplete"),
+                    (conditions::DEGRADED, true, "ReplicasUnready"),
+                ],
+            ),
+            (
+                "scaling up",
+                sts(2, 2, &rolled),
+                vec![
+                    (conditions::AVAILABLE, false, "MinimumReplicasUnavailable"),
+                    (conditions::PROGRESSING, true, "Scaling"),
+                    (conditions::DEGRADED, false, "ReplicasReady"),
+                ],
+            ),
+            (
+                "scaling down",
+                sts(4, 4, &rolled),
+                vec![
+                    (conditions::PROGRESSING, true, "Scaling"),
+                    (conditions::SCALING_DOWN, true, "RetiringReplica"),
+                ],
+            ),
+            (
+                "outdated spec",
+                sts(3, 2, &outdated),
+                vec![
+                    (conditions::PROGRESSING, true, "RollingUpdate"),
+                    // unready replicas are expected while rolling.
+                    (conditions::DEGRADED, false, "ReplicasReady"),
+                ],
+            ),
+            (
+                "rolling revision",
+                sts(3, 3, &revising),
+                vec![(conditions::PROGRESSING, true, "RollingUpdate")],
+            ),
+        ];
+        for (name, found, want) in cases {
+            let conds = observed_conditions(&pool, &found);
+            for (type_, status, reason) in want {
+                let cond = conditions::find(&conds, type_).unwrap();
+                assert_eq!(
+                    (cond.status == "True", cond.reason.as_deref()),
+                    (status, Some(reason)),
+                    "{}: {}",
+                    name,
+                    type_
+                );
             }
         }
     }
 
-    // if nth replica is no longer ready scale down sts
-    // and remove any `>n` fields from the status scale down map.
+    #[test]
+    fn finds_the_unready_top_ordinals() {
+        let pod = |name: &str, ready: &str| -> Pod {
+            serde_json::from_value(json!({
+                "metadata": { "name": name },
+                "status": { "conditions": [{ "type": "Ready", "status": ready }] },
+            }))
+            .unwrap()
+        };
+        let pods = vec![
+            pod("ingester-abc-2", "True"),
+            pod("ingester-abc-0", "True"),
+            pod("ingester-abc-1", "False"),
+            pod("ingester-abc-3", "False"),
+        ];
+        assert_eq!(ready_ordinals("ingester-abc", &pods), vec![0, 2]);
 
-    // check if status has [n -> hash(config)] in the status indicating the scaledown has been run.
-    // If not, run scaledown and add this field.
+        // (case, ready pods, ready replicas of the sts, unready top ordinals)
+        let cases = vec![
+            ("unready top", vec![0, 2], 2, 1),
+            ("unready tail", vec![0, 1], 2, 2),
+            ("all ready", vec![0, 1, 2, 3], 4, 0),
+            // ready pods which haven't been mirrored yet.
+            ("lagging", vec![], 4, 0),
+        ];
+        let mut pool = fixtures::found_pool(&fixtures::gratefulset(|_| {}), 4, 0);
+        for (name, ready, sts_ready, want) in cases {
+            let status = pool.status.as_mut().unwrap();
+            status.ready_ordinals = ready;
+            status.sts_status.ready_replicas = Some(sts_ready);
+            assert_eq!(pool.unready_top(), want, "{}", name);
+        }
 
-    Ok(ReconcilerAction {
-        // try again in 5min
-        requeue_after: Some(Duration::from_secs(300)),
-    })
+        // unconfirmed replicas count as unready.
+        let status = pool.status.as_mut().unwrap();
+        status.ready_ordinals = vec![0, 1, 2, 3];
+        status.scale_up_records.remove(&3);
+        assert_eq!(pool.unready_top(), 1);
+    }
+
+    #[test]
+    fn runs_scale_down_for_running_pods() {
+        let pod = |phase: &str| Pod {
+            status: Some(serde_json::from_value(json!({ "phase": phase })).unwrap()),
+            ..Default::default()
+        };
+        assert!(is_running(Some(&pod("Running"))));
+        // held in its init container by the revoked lock.
+        assert!(!is_running(Some(&pod("Pending"))));
+        assert!(!is_running(Some(&pod("Failed"))));
+        assert!(!is_running(Some(&Pod::default())));
+        assert!(!is_running(None));
+    }
+
+    #[test]
+    fn pool_hash_is_stable() {
+        // Pools are named by their hash: it must not change across builds. Changing the
+        // immutable field set or defaults changes it too, in which case existing pools are
+        // still adopted by comparing their fields (see `GratefulSet::current_pool`).
+        assert_eq!(spec(|_| {}).pool_hash(), "023dec3e53");
+    }
+
+    #[test]
+    fn config_hash_is_stable() {
+        // Scale up/down records are keyed by the config hash, so a hash that moved between
+        // builds would rerun every hook after an operator upgrade.
+        let base = spec(|_| {});
+        assert_eq!(
+            base.config_hash(),
+            spec(|x| x["replicas"] = json!(7)).config_hash()
+        );
+        assert_eq!(base.config_hash(), "d12a96e648290503");
+    }
 }
@@ -0,0 +1,340 @@
+use crate::errors::*;
+use crate::gsp::GratefulSetPool;
+use futures::{future::BoxFuture, FutureExt};
+use kube::api::Meta;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ScaleDown runs application specific behavior when a replica is retired from a pool.
+///
+/// It is called after the lock for `ordinal` has been removed from the `<pool>-lock`
+/// configmap, but before the underlying statefulset is scaled down. The retired pod will not
+/// be able to restart, so this is the last chance to flush state, leave a ring, etc.
+/// Successful runs are recorded in the pool status, but implementations must still be
+/// idempotent: a failure after the hook returns may cause it to be retried.
+/// It isn't called for replicas whose pod isn't running (e.g. it's gone, or restarted & is held
+/// in its init container by the revoked lock): there's nothing left to shut down. Deleting the
+/// pod of a replica whose hook keeps failing skips the hook for it.
+pub trait ScaleDown: Send + Sync {
+    fn scale_down(&self, pool: &GratefulSetPool, ordinal: i32) -> BoxFuture<'static, Result<()>>;
+}
+
+/// The default ScaleDown: no application specific behavior.
+pub struct NoopScaleDown;
+
+impl ScaleDown for NoopScaleDown {
+    fn scale_down(&self, _pool: &GratefulSetPool, _ordinal: i32) -> BoxFuture<'static, Result<()>> {
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+/// ScaleUp gates the growth of a pool on application specific readiness.
+///
+/// It is called for each ready replica after its lock has been added to the `<pool>-lock`
+/// configmap and the underlying statefulset has been scaled up to include it. Returning
+/// `Ok(false)` blocks further progress (adding the next replica, retiring old pool replicas)
+/// and the hook will be polled again later. Use it to wait for e.g. a ring member to become
+/// ACTIVE or a kafka broker to join the ISR. Confirmations are recorded in the pool status
+/// per ordinal and config hash.
+pub trait ScaleUp: Send + Sync {
+    fn scale_up(&self, pool: &GratefulSetPool, ordinal: i32) -> BoxFuture<'static, Result<bool>>;
+}
+
+/// The default ScaleUp: a replica is confirmed once the statefulset reports it ready.
+pub struct NoopScaleUp;
+
+impl ScaleUp for NoopScaleUp {
+    fn scale_up(&self, _pool: &GratefulSetPool, _ordinal: i32) -> BoxFuture<'static, Result<bool>> {
+        futures::future::ready(Ok(true)).boxed()
+    }
+}
+
+/// HttpScaleDownSpec declares an HTTP request to send to a replica when it's retired,
+/// e.g. `POST /ingester/shutdown` for Loki ingesters.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpScaleDownSpec {
+    pub path: String,
+    pub port: i32,
+    /// HTTP method, defaults to POST.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Status codes considered successful. Defaults to any 2xx.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected_status_codes: Vec<u16>,
+    /// Per request timeout, defaults to 30s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+    /// Number of retries after the first failed request, defaults to 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+}
+
+impl HttpScaleDownSpec {
+    // The replica is addressed via the governing service of its statefulset:
+    // <pool>-<n>.<serviceName>.<namespace>.svc
+    pub fn url(&self, pool: &GratefulSetPool, ordinal: i32) -> String {
+        format!(
+            "http://{}-{}.{}.{}.svc:{}/{}",
+            Meta::name(pool),
+            ordinal,
+            pool.spec.sts_spec.service_name,
+            Meta::namespace(pool).unwrap_or_else(|| String::from("default")),
+            self.port,
+            self.path.trim_start_matches('/'),
+        )
+    }
+
+    fn is_expected(&self, status: reqwest::StatusCode) -> bool {
+        if self.expected_status_codes.is_empty() {
+            status.is_success()
+        } else {
+            self.expected_status_codes.contains(&status.as_u16())
+        }
+    }
+
+    // Blocks until the request succeeds or retries are exhausted.
+    fn call(&self, url: &str) -> Result<()> {
+        let method = reqwest::Method::from_bytes(
+            self.method
+                .as_deref()
+                .unwrap_or("POST")
+                .to_uppercase()
+                .as_bytes(),
+        )
+        .chain_err(|| format!("invalid scaleDown method {:?}", self.method))?;
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(self.timeout_seconds.unwrap_or(30)))
+            .build()?;
+
+        let attempts = self.retries.unwrap_or(3) + 1;
+        let mut last_err = None;
+        for attempt in 1..=attempts {
+            match client.request(method.clone(), url).send() {
+                Ok(resp) if self.is_expected(resp.status()) => {
+                    info!("scaleDown {} {} succeeded: {}", method, url, resp.status());
+                    return Ok(());
+                }
+                Ok(resp) => {
+                    warn!(
+                        "scaleDown {} {} returned unexpected status {} (attempt {}/{})",
+                        method,
+                        url,
+                        resp.status(),
+                        attempt,
+                        attempts
+                    );
+                    last_err = Some(Error::from(format!(
+                        "unexpected status {} from {}",
+                        resp.status(),
+                        url
+                    )));
+                }
+                Err(e) => {
+                    warn!(
+                        "scaleDown {} {} failed: {} (attempt {}/{})",
+                        method, url, e, attempt, attempts
+                    );
+                    last_err = Some(e.into());
+                }
+            }
+            if attempt < attempts {
+                std::thread::sleep(Duration::from_secs(u64::from(attempt)));
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::from("scaleDown made no attempts")))
+    }
+}
+
+/// HttpScaleDown runs the `scaleDown` request declared on the pool spec, if any.
+/// The blocking request is run on its own thread so it never stalls the reconciler.
+pub struct HttpScaleDown;
+
+impl ScaleDown for HttpScaleDown {
+    fn scale_down(&self, pool: &GratefulSetPool, ordinal: i32) -> BoxFuture<'static, Result<()>> {
+        let spec = match &pool.spec.scale_down {
+            Some(spec) => spec.clone(),
+            None => return futures::future::ready(Ok(())).boxed(),
+        };
+        let url = spec.url(pool, ordinal);
+        let (tx, rx) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(spec.call(&url));
+        });
+        rx.map(|res| res.unwrap_or_else(|_| Err(Error::from("scaleDown thread exited"))))
+            .boxed()
+    }
+}
+
+/// Hooks are the pluggable application behaviors used by the controllers.
+#[derive(Clone)]
+pub struct Hooks {
+    pub scale_up: Arc<dyn ScaleUp>,
+    pub scale_down: Arc<dyn ScaleDown>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            scale_up: Arc::new(NoopScaleUp),
+            scale_down: Arc::new(HttpScaleDown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::cmp::min;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    // Serves `statuses` in turn (repeating the last one) on a local port, recording the method
+    // & path of each request. Returns the server's base url.
+    fn serve(statuses: Vec<u16>) -> (String, Requests) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            let make_svc = make_service_fn(move |_| {
+                let (seen, statuses) = (seen.clone(), statuses.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let mut seen = seen.lock().unwrap();
+                        seen.push(format!("{} {}", req.method(), req.uri().path()));
+                        let status = statuses[min(seen.len(), statuses.len()) - 1];
+                        let mut resp = Response::new(Body::empty());
+                        *resp.status_mut() = hyper::StatusCode::from_u16(status).unwrap();
+                        async move { Ok::<_, Infallible>(resp) }
+                    }))
+                }
+            });
+            let mut rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async { Server::from_tcp(listener)?.serve(make_svc).await })
+                .unwrap();
+        });
+        (url, requests)
+    }
+
+    fn spec(edit: impl Fn(&mut HttpScaleDownSpec)) -> HttpScaleDownSpec {
+        let mut spec = HttpScaleDownSpec {
+            path: String::from("/ingester/shutdown"),
+            port: 3100,
+            retries: Some(1),
+            ..Default::default()
+        };
+        edit(&mut spec);
+        spec
+    }
+
+    #[test]
+    fn addresses_replicas_via_the_governing_service() {
+        let pool = fixtures::gratefulset(|_| {}).pool();
+        assert_eq!(
+            spec(|_| {}).url(&pool, 2),
+            format!(
+                "http://{}-2.ingester.loki.svc:3100/ingester/shutdown",
+                Meta::name(&pool)
+            )
+        );
+    }
+
+    #[test]
+    fn calls_the_scale_down_endpoint() {
+        // name, spec edit, statuses served, expected requests, expected error.
+        type Case = (
+            &'static str,
+            Box<dyn Fn(&mut HttpScaleDownSpec)>,
+            Vec<u16>,
+            Vec<&'static str>,
+            Option<&'static str>,
+        );
+        let cases: Vec<Case> = vec![
+            (
+                "defaults",
+                Box::new(|_| {}),
+                vec![204],
+                vec!["POST /ingester/shutdown"],
+                None,
+            ),
+            (
+                "method",
+                Box::new(|x| x.method = Some(String::from("put"))),
+                vec![200],
+                vec!["PUT /ingester/shutdown"],
+                None,
+            ),
+            (
+                "invalid method",
+                Box::new(|x| x.method = Some(String::from("P OST"))),
+                vec![200],
+                vec![],
+                Some("invalid scaleDown method"),
+            ),
+            (
+                "retried",
+                Box::new(|_| {}),
+                vec![503, 204],
+                vec!["POST /ingester/shutdown", "POST /ingester/shutdown"],
+                None,
+            ),
+            (
+                "retries exhausted",
+                Box::new(|_| {}),
+                vec![503],
+                vec!["POST /ingester/shutdown", "POST /ingester/shutdown"],
+                Some("unexpected status 503"),
+            ),
+            (
+                "expected status codes",
+                Box::new(|x| {
+                    x.expected_status_codes = vec![204];
+                    x.retries = Some(0);
+                }),
+                vec![200],
+                vec!["POST /ingester/shutdown"],
+                Some("unexpected status 200"),
+            ),
+            (
+                "non 2xx expected",
+                Box::new(|x| x.expected_status_codes = vec![404]),
+                vec![404],
+                vec!["POST /ingester/shutdown"],
+                None,
+            ),
+        ];
+
+        for (name, edit, statuses, want, error) in cases {
+            let (url, requests) = serve(statuses);
+            let spec = spec(edit);
+            let res = spec.call(&format!("{}{}", url, spec.path));
+            match error {
+                Some(error) => assert!(
+                    res.as_ref()
+                        .err()
+                        .is_some_and(|e| e.to_string().contains(error)),
+                    "{}: expected {:?}, got {:?}",
+                    name,
+                    error,
+                    res
+                ),
+                None => assert!(res.is_ok(), "{}: {:?}", name, res),
+            }
+            assert_eq!(*requests.lock().unwrap(), want, "{}", name);
+        }
+    }
+
+    #[test]
+    fn skips_pools_without_a_scale_down() {
+        let pool = fixtures::gratefulset(|_| {}).pool();
+        assert!(futures::executor::block_on(HttpScaleDown.scale_down(&pool, 0)).is_ok());
+    }
+}
@@ -0,0 +1,21 @@
+use std::collections::BTreeMap;
+
+/// Identifies the GratefulSet which (transitively) owns an object.
+/// Set on pools and everything they manage: statefulsets, locks configmaps & pods.
+pub const OWNER: &str = "owner.pikach.us";
+
+/// Identifies the pool (by hash of its immutable fields) an object belongs to.
+pub const POOL_HASH: &str = "pool-hash.pikach.us";
+
+/// Labels for a pool of the `owner` GratefulSet and all of its children.
+pub fn pool_labels(owner: &str, hash: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(String::from(OWNER), String::from(owner));
+    labels.insert(String::from(POOL_HASH), String::from(hash));
+    labels
+}
+
+/// Selects everything belonging to the `owner` GratefulSet, across all of its pools.
+pub fn owner_selector(owner: &str) -> String {
+    format!("{}={}", OWNER, owner)
+}
@@ -1,9 +1,21 @@
 #[macro_use]
 extern crate error_chain;
 
+pub mod conditions;
+pub mod crd;
+pub mod defaults;
+pub mod diff;
+#[cfg(test)]
+mod fixtures;
 pub mod gs;
 pub mod gsp;
+pub mod hooks;
+pub mod labels;
 pub mod manager;
+pub mod plan;
+pub mod schema;
+pub mod volumes;
+pub mod webhook;
 pub mod errors {
     // Create the Error, ErrorKind, ResultExt, and Result types
     error_chain! {
@@ -49,5 +61,7 @@ Examples:
 
 Notes:
   - uses pikach.us prefixing for API groups
-  - injects an "owner.pikach.us={}" label onto GratefulSetPools in order to be able to effectively query children (cannot do it via owner refs AFAICT).
+  - injects "owner.pikach.us={gratefulset}" and "pool-hash.pikach.us={hash}" labels onto GratefulSetPools
+    and everything they manage (sts, configmaps, pods) in order to be able to effectively query children
+    (cannot do it via owner refs AFAICT).
 */
@@ -1,52 +1,55 @@
-use gratefulset::{errors::*, gs::*};
-use kube::api::{Api, ListParams, Meta, WatchEvent};
-use kube::Client;
-
-// `block_on` blocks the current thread until the provided future has run to
-// completion. Other executors provide more complex behavior, like scheduling
-// multiple futures onto the same thread.
-use futures::{executor::block_on, Future};
-use futures::{StreamExt, TryStreamExt};
-
-fn main() {
-    block_on(libmain());
+use gratefulset::{crd, gs::*, hooks::Hooks, webhook};
+
+// kube's client and the controller runtime are built on tokio, so they need its
+// runtime (rather than a plain `block_on`) to drive them.
+#[tokio::main]
+async fn main() {
+    match std::env::args().nth(1).as_deref() {
+        None | Some("run") => {
+            let client = kube::Client::try_default().await.expect("create client");
+            let (_, drainer) = Manager::new(client, Hooks::default()).await;
+            drainer.await
+        }
+        Some("crd") => print!("{}", crd::manifests().expect("render crds")),
+        Some("install-crds") => {
+            let client = kube::Client::try_default().await.expect("create client");
+            crd::install(client).await.expect("install crds");
+        }
+        Some("webhook") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            let read = |name: &str| {
+                let path = flag(&args, name).unwrap_or_else(|| usage(&format!("missing {}", name)));
+                std::fs::read(&path).unwrap_or_else(|e| usage(&format!("reading {}: {}", path, e)))
+            };
+            let (cert, key) = (read("--cert"), read("--key"));
+            let addr = flag(&args, "--addr")
+                .unwrap_or_else(|| String::from("0.0.0.0:8443"))
+                .parse()
+                .unwrap_or_else(|e| usage(&format!("invalid --addr: {}", e)));
+            webhook::serve(addr, &cert, &key)
+                .await
+                .expect("serve webhook");
+        }
+        Some(cmd) => usage(&format!("unknown command {:?}", cmd)),
+    }
 }
 
-async fn libmain() -> std::pin::Pin<Box<dyn Future<Output = ()> + Send>> {
-    let client = kube::Client::try_default().await.expect("create client");
-    let (_, drainer) = Manager::new(client).await;
-    drainer
+// The value following `name` in `args`, e.g. `--cert <path>`.
+fn flag(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1).cloned())
 }
 
-async fn libwatch() -> Result<()> {
-    // Read the environment to find config for kube client.
-    // Note that this tries an in-cluster configuration first,
-    // then falls back on a kubeconfig file.
-    let client = Client::try_default().await?;
-
-    // Get a strongly typed handle to the Kubernetes API for interacting
-    // with gratefulsets in the "default" namespace.
-    let gs: Api<GratefulSet> = Api::namespaced(client, "default");
-
-    // Start a watch call for gratefulsets matching our name
-    let lp = ListParams::default()
-        .fields(&format!("metadata.name={}", "my-gratefulset"))
-        .timeout(10);
-    let mut stream = gs.watch(&lp, "0").await?.boxed();
-
-    // Observe the gratefulsets phase for 10 seconds
-    while let Some(status) = stream.try_next().await? {
-        match status {
-            WatchEvent::Added(o) => println!("Added {}", Meta::name(&o)),
-            WatchEvent::Modified(o) => {
-                let s = o.status.as_ref().expect("status exists on gratefulset");
-                println!("Modified: {}: {:#?}", Meta::name(&o), s);
-            }
-            WatchEvent::Deleted(o) => println!("Deleted {}", Meta::name(&o)),
-            WatchEvent::Error(e) => println!("Error {}", e),
-            _ => {}
-        }
-    }
-
-    Ok(())
+fn usage(msg: &str) -> ! {
+    eprintln!(
+        "{}\n\nusage: gratefulset [run|crd|install-crds|webhook]\n  \
+         run           run the controllers (default)\n  \
+         crd           print the CRD manifests\n  \
+         install-crds  install or update the CRDs in the cluster\n  \
+         webhook --cert <pem> --key <pem> [--addr 0.0.0.0:8443]\n                \
+         serve the validating admission webhook at /validate over HTTPS",
+        msg
+    );
+    std::process::exit(1);
 }
@@ -1,7 +1,11 @@
 use crate::errors::*;
+use crate::hooks::Hooks;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use k8s_openapi::Resource;
+use kube::api::Meta;
 use kube::client::Client;
 use kube_runtime::controller::{Context, ReconcilerAction};
-use log::{warn};
+use log::warn;
 use std::time::Duration;
 
 // Context for our reconciler
@@ -9,6 +13,8 @@ use std::time::Duration;
 pub struct Data {
     /// kubernetes client
     pub client: Client,
+    /// application specific scaling behavior
+    pub hooks: Hooks,
 }
 
 pub fn error_policy(error: &Error, _ctx: Context<Data>) -> ReconcilerAction {
@@ -17,3 +23,16 @@ pub fn error_policy(error: &Error, _ctx: Context<Data>) -> ReconcilerAction {
         requeue_after: Some(Duration::from_secs(60)),
     }
 }
+
+/// Builds an owner reference marking `owner` as the managing controller of a child object,
+/// so that children are garbage collected along with it.
+pub fn controller_ref<K: Meta + Resource>(owner: &K) -> OwnerReference {
+    OwnerReference {
+        api_version: K::API_VERSION.to_string(),
+        kind: K::KIND.to_string(),
+        name: Meta::name(owner),
+        uid: owner.meta().uid.clone().unwrap_or_default(),
+        controller: Some(true),
+        block_owner_deletion: Some(true),
+    }
+}
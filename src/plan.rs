@@ -0,0 +1,1406 @@
+use crate::diff::{Change, SpecDiff};
+use crate::errors::*;
+use crate::gs::GratefulSet;
+use crate::gsp::{held_locks, settled_ready, GratefulSetPool, MigrationStrategy};
+use crate::volumes;
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::Meta;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Planning of rollouts, separated from the Kubernetes I/O carrying them out.
+//
+// The planners are pure: given the observed state of the world they return the next actions to
+// take. Each reconciler observes, plans & executes the actions in order, then does it all over
+// again once the world has settled.
+// - gs::reconcile plans the pools of a GratefulSet (ObservedGratefulSet), moving its replicas
+//   from the old pools to the pool matching its current spec.
+// - gsp::reconcile plans the statefulset & locks of a pool (ObservedPool), scaling it one replica
+//   at a time while running the ScaleUp & ScaleDown hooks.
+
+/// The observed state of a pool, as relevant to the rollout.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolState {
+    pub name: String,
+    /// Whether the pool has been created.
+    pub exists: bool,
+    /// Desired replicas of the pool.
+    pub replicas: i32,
+    /// Replicas of the pool's statefulset.
+    pub observed_replicas: i32,
+    /// Ready replicas of the pool (which have been confirmed by ScaleUp).
+    pub ready: i32,
+    /// Unready replicas which are known to be the pool's highest ordinals.
+    pub unready_top: i32,
+}
+
+impl PoolState {
+    pub fn observe(p: &GratefulSetPool) -> Self {
+        PoolState {
+            name: Meta::name(p),
+            exists: true,
+            replicas: p.spec.sts_spec.replicas.unwrap_or(1),
+            observed_replicas: p.status.clone().unwrap_or_default().sts_status.replicas,
+            ready: p.ready_replicas(),
+            unready_top: p.unready_top(),
+        }
+    }
+
+    // Whether the pool has caught up with its desired replicas.
+    fn settled(&self) -> bool {
+        self.replicas == self.observed_replicas
+    }
+}
+
+/// A step of a GratefulSet rollout, carried out by gs::reconcile.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Salt the pool hash: the desired pool's name is taken by a pool of a different spec.
+    BumpCollisionCount,
+    /// Create the desired pool with the given replicas.
+    CreatePool { pool: String, replicas: i32 },
+    /// Update a pool to the desired spec, keeping its replicas.
+    UpdatePool { pool: String },
+    /// Set the desired replicas of a pool.
+    SetReplicas { pool: String, replicas: i32 },
+    /// Delete a drained pool.
+    DeletePool { pool: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::BumpCollisionCount => write!(f, "bump the collision count"),
+            Action::CreatePool { pool, replicas } => {
+                write!(f, "create pool {} with {} replicas", pool, replicas)
+            }
+            Action::UpdatePool { pool } => write!(f, "update pool {} to the desired spec", pool),
+            Action::SetReplicas { pool, replicas } => {
+                write!(f, "scale pool {} to {} replicas", pool, replicas)
+            }
+            Action::DeletePool { pool } => write!(f, "delete pool {}", pool),
+        }
+    }
+}
+
+/// The observed state of a GratefulSet: the GratefulSet itself & the pools it owns.
+pub struct ObservedGratefulSet<'a> {
+    pub gs: &'a GratefulSet,
+    pub pools: &'a [GratefulSetPool],
+}
+
+impl ObservedGratefulSet<'_> {
+    /// Plans the next step of the GratefulSet rollout. No actions means there's nothing to do
+    /// for now.
+    ///
+    /// The current pool is brought up to date with the spec (sans replicas) first, waiting for
+    /// it to roll before moving replicas to it. When the replicas are lowered along with a spec
+    /// change which rolls the pool, the pool is scaled down first so only the remaining replicas
+    /// are rolled. Scaling up happens after the roll, so new replicas start with the new spec.
+    /// See `rollout` for the rest.
+    ///
+    /// An aborted migration moves the replicas back to the previous pool instead, leaving its
+    /// spec as is. While the GratefulSet is paused, no replicas move across pools (nor back):
+    /// the current pool is still updated, and scaled as long as it's the only pool.
+    pub fn plan(&self) -> Vec<Action> {
+        // Rolling back: the previous pool is the desired one, as is. Without a previous pool
+        // there's nothing to roll back to.
+        if self.gs.spec.abort {
+            if self.gs.spec.paused {
+                return vec![];
+            }
+            return match self.gs.previous_pool(self.pools) {
+                Some(previous) => self.migrate(PoolState::observe(previous)),
+                None => vec![],
+            };
+        }
+
+        let desired = self.gs.spec.sts_spec.replicas.unwrap_or(1);
+        let current = match self.gs.current_pool(self.pools) {
+            Some(cur) if self.outdated(cur) => {
+                let state = PoolState::observe(cur);
+                if self.rolls(cur) {
+                    if state.replicas > desired {
+                        return vec![scale(&state, desired)];
+                    }
+                    // Wait for a scale down to land before rolling.
+                    if state.observed_replicas > state.replicas {
+                        return vec![];
+                    }
+                }
+                return vec![Action::UpdatePool {
+                    pool: Meta::name(cur),
+                }];
+            }
+            Some(cur) => PoolState::observe(cur),
+            None => {
+                let name = Meta::name(&self.gs.pool());
+                if self.pools.iter().any(|p| Meta::name(p) == name) {
+                    return vec![Action::BumpCollisionCount];
+                }
+                PoolState {
+                    name,
+                    ..Default::default()
+                }
+            }
+        };
+
+        if self.gs.spec.paused && self.pools.iter().any(|p| Meta::name(p) != current.name) {
+            return vec![];
+        }
+        self.migrate(current)
+    }
+
+    // Plans moving the replicas from the other pools to the `current` one.
+    fn migrate(&self, current: PoolState) -> Vec<Action> {
+        let desired = self.gs.spec.sts_spec.replicas.unwrap_or(1);
+        let mut old: Vec<&GratefulSetPool> = self
+            .pools
+            .iter()
+            .filter(|p| Meta::name(*p) != current.name)
+            .collect();
+        old.sort_by_key(|p| p.metadata.creation_timestamp.clone());
+        let budget = self
+            .gs
+            .spec
+            .rollout_strategy
+            .clone()
+            .unwrap_or_default()
+            .budget(desired)
+            // rejected by the admission webhook, fall back to one replica at a time.
+            .unwrap_or_default();
+        rollout(
+            desired,
+            budget,
+            &current,
+            &old.into_iter().map(PoolState::observe).collect::<Vec<_>>(),
+        )
+    }
+
+    // Whether updating the pool to the spec rolls its replicas.
+    fn rolls(&self, cur: &GratefulSetPool) -> bool {
+        SpecDiff::new(&self.gs.spec.sts_spec, &cur.spec.sts_spec).rolls()
+    }
+
+    // Whether the current pool differs from the spec by more than its replicas.
+    fn outdated(&self, cur: &GratefulSetPool) -> bool {
+        self.rolls(cur) || cur.spec.scale_down != self.gs.spec.scale_down
+    }
+}
+
+/// The limits of a migration step across pools, resolved from the RolloutStrategy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    /// Replicas which may be unready, below the desired replicas.
+    pub max_unavailable: i32,
+    /// Replicas which may be requested above the desired replicas.
+    pub max_surge: i32,
+}
+
+impl Default for Budget {
+    // One replica at a time, removing an old replica before adding a new one.
+    fn default() -> Self {
+        Budget {
+            max_unavailable: 1,
+            max_surge: 0,
+        }
+    }
+}
+
+/// Plans the next step of rolling `desired` replicas into the `current` pool, out of the
+/// `old` pools (sorted oldest first). No actions means there's nothing to do for now: either
+/// the rollout is complete or pools are still settling.
+///
+/// Each step retires old replicas, oldest pool first, while keeping at least
+/// `desired - max_unavailable` replicas ready. A statefulset removes its highest ordinals first,
+/// so unready old replicas only go for free (like a Deployment's cleanup of unhealthy replicas)
+/// when they're known to be the highest ordinals of their pool. Any other retired replica counts
+/// against `max_unavailable`, as it may well be ready. Replicas are added to the current pool
+/// (up to `desired`) as long as no more than `desired + max_surge` are requested; retired
+/// replicas only make room for new ones once they're gone. With the default budget this is a
+/// statefulset rollout: an old replica is removed before a new one is added.
+/// Once the old pools are drained, the current pool is scaled to `desired` & the old pools are
+/// deleted.
+pub fn rollout(
+    desired: i32,
+    budget: Budget,
+    current: &PoolState,
+    old: &[PoolState],
+) -> Vec<Action> {
+    // One step at a time: wait for the previous one to land.
+    if !current.settled() || old.iter().any(|p| !p.settled()) {
+        return vec![];
+    }
+
+    let old_replicas: i32 = old.iter().map(|p| p.replicas).sum();
+    if old_replicas == 0 {
+        let mut actions: Vec<Action> = old
+            .iter()
+            .map(|p| Action::DeletePool {
+                pool: p.name.clone(),
+            })
+            .collect();
+        if current.replicas != desired {
+            actions.push(scale(current, desired));
+        }
+        return actions;
+    }
+
+    let old_ready: i32 = old.iter().map(|p| p.ready).sum();
+    let ready = current.ready + old_ready;
+    let requested = current.replicas + old_replicas;
+    let mut actions = vec![];
+
+    let mut retire = max(ready - (desired - budget.max_unavailable), 0);
+    for p in old {
+        let n = min(retire, p.replicas - p.unready_top);
+        retire -= n;
+        if p.unready_top + n > 0 {
+            actions.push(scale(p, p.replicas - p.unready_top - n));
+        }
+    }
+
+    let add = min(
+        desired + budget.max_surge - requested,
+        desired - current.replicas,
+    );
+    if add > 0 {
+        actions.push(scale(current, current.replicas + add));
+    }
+    // No actions: waiting for replicas to become ready.
+    actions
+}
+
+// Scales a pool, creating it if needed.
+fn scale(p: &PoolState, replicas: i32) -> Action {
+    if p.exists {
+        Action::SetReplicas {
+            pool: p.name.clone(),
+            replicas,
+        }
+    } else {
+        Action::CreatePool {
+            pool: p.name.clone(),
+            replicas,
+        }
+    }
+}
+
+/// A step of a pool rollout, carried out by gsp::reconcile.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolAction {
+    /// Create the locks configmap, holding the locks of the desired replicas.
+    CreateLocks,
+    /// Create the statefulset with the given replicas: none for a new pool, or its last observed
+    /// replicas when it's being recreated.
+    CreateStatefulSet { replicas: i32 },
+    /// Update the statefulset to the desired spec, keeping its replicas. This rolls its pods.
+    UpdateStatefulSet,
+    /// Grow the PVCs of every ordinal of the pool to the storage in `grown` (by claim name).
+    ExpandVolumes { grown: BTreeMap<String, String> },
+    /// Delete the statefulset orphaning its pods, so it's recreated with the desired spec.
+    RecreateStatefulSet,
+    /// Run ScaleUp for the ready replicas which haven't been confirmed for the current config.
+    /// Execution stops here unless every replica ends up confirmed.
+    ConfirmScaleUp,
+    /// Forget the ScaleDown records of the replicas from `from` on, which are being brought back.
+    ForgetScaleDowns { from: i32 },
+    /// Grant the locks of replicas `0..=n`.
+    AddLock(i32),
+    /// Revoke the lock of replica n, so it can't restart.
+    RevokeLock(i32),
+    /// Run ScaleDown for replica n & record it.
+    RunScaleDown(i32),
+    /// Set the statefulset replicas.
+    ScaleStatefulSet(i32),
+}
+
+impl fmt::Display for PoolAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolAction::CreateLocks => write!(f, "create the locks configmap"),
+            PoolAction::CreateStatefulSet { replicas } => {
+                write!(f, "create the statefulset with {} replicas", replicas)
+            }
+            PoolAction::UpdateStatefulSet => write!(f, "roll the statefulset to the desired spec"),
+            PoolAction::ExpandVolumes { grown } => write!(f, "expand the volumes to {:?}", grown),
+            PoolAction::RecreateStatefulSet => write!(f, "recreate the statefulset"),
+            PoolAction::ConfirmScaleUp => write!(f, "confirm replicas via ScaleUp"),
+            PoolAction::ForgetScaleDowns { from } => {
+                write!(f, "forget ScaleDown records from replica {}", from)
+            }
+            PoolAction::AddLock(n) => write!(f, "add the lock for replica {}", n),
+            PoolAction::RevokeLock(n) => write!(f, "revoke the lock for replica {}", n),
+            PoolAction::RunScaleDown(n) => write!(f, "run ScaleDown for replica {}", n),
+            PoolAction::ScaleStatefulSet(n) => write!(f, "scale the statefulset to {}", n),
+        }
+    }
+}
+
+/// The observed state of a pool: the pool itself, its statefulset & its locks configmap.
+pub struct ObservedPool<'a> {
+    pub pool: &'a GratefulSetPool,
+    pub sts: Option<&'a StatefulSet>,
+    pub locks: Option<&'a ConfigMap>,
+}
+
+impl ObservedPool<'_> {
+    /// Plans the next step of the pool rollout. No actions means there's nothing to do for now:
+    /// either the statefulset matches the pool or it's still settling.
+    ///
+    /// The statefulset is brought up to date with the spec (sans replicas) first. Then the
+    /// replicas are scaled one at a time.
+    ///
+    /// Scaling up adds one replica at a time:
+    /// 1) Wait for every existing replica to be confirmed by the ScaleUp implementation.
+    /// 2) Add the lock for the new replica to the locks configmap & increment the sts replicas.
+    ///
+    /// Scaling down retires the nth replica as follows:
+    /// 1) Remove the lock for n from the locks configmap so it can't restart.
+    /// 2) Run the ScaleDown hook for n unless it's been recorded for this config.
+    /// 3) Once n is no longer ready, decrement the sts replicas.
+    pub fn plan(&self) -> Result<Vec<PoolAction>> {
+        let gsp = self.pool;
+        let held = self
+            .locks
+            .map(|cm| held_locks(&cm.data.clone().unwrap_or_default()));
+        let status = gsp.status.clone().unwrap_or_default();
+
+        let found = match self.sts {
+            Some(found) => found,
+            // The sts doesn't exist yet: bootstrap it along with its locks configmap.
+            None => {
+                let mut actions = vec![];
+                if held.is_none() {
+                    actions.push(PoolAction::CreateLocks);
+                }
+                // The sts starts empty & grows through ScaleUp like any other. A pool which has
+                // had a sts (i.e. recreated after an orphaning delete) picks up at its last
+                // observed replicas instead, re-adopting the orphaned pods.
+                let replicas = gsp.status.as_ref().map_or(0, |s| s.sts_status.replicas);
+                actions.push(PoolAction::CreateStatefulSet { replicas });
+                return Ok(actions);
+            }
+        };
+        let found_spec = found.spec.clone().unwrap_or_default();
+        let ready = settled_ready(found);
+
+        let diff = SpecDiff::new(&gsp.sts_spec(), &found_spec);
+        match diff.change() {
+            // If the specs are equal, all that's left is to confirm the replicas via ScaleUp.
+            Change::None => return Ok(self.confirm(found)),
+            Change::Immutable => {
+                // Growing volumes: expand the PVCs, then recreate the sts with the new templates.
+                if let Some(grown) = volumes::expansion(&gsp.sts_spec(), &found_spec) {
+                    return Ok(vec![
+                        PoolAction::ExpandVolumes { grown },
+                        PoolAction::RecreateStatefulSet,
+                    ]);
+                }
+                // The pool's immutable fields were changed in place: recreate the sts with them.
+                if gsp.spec.migration_strategy == Some(MigrationStrategy::Recreate) {
+                    return Ok(vec![PoolAction::RecreateStatefulSet]);
+                }
+                // The immutable fields identify the pool, so this means the sts was modified out from under us.
+                return Err(Error::from(format!(
+                    "statefulset {} can't be updated in place: {}",
+                    Meta::name(gsp),
+                    diff
+                )));
+            }
+            // The spec has changed. Update it & start the underlying rollout (sans replicas).
+            Change::Template => return Ok(vec![PoolAction::UpdateStatefulSet]),
+            Change::Replicas => {}
+        }
+
+        // From this point on, the only difference is the number of specified replicas.
+        let desired = gsp.spec.sts_spec.replicas.unwrap_or(1);
+        let replicas = found_spec.replicas.unwrap_or(1);
+        let current = found
+            .status
+            .as_ref()
+            .and_then(|x| x.current_replicas)
+            .unwrap_or(0);
+
+        // Wait for a spec difference to finish rolling out.
+        if replicas != current {
+            return Ok(vec![]);
+        }
+
+        let mut actions = vec![];
+        if desired > replicas {
+            let unconfirmed = self.unconfirmed(found);
+            if unconfirmed.last().is_some_and(|o| *o >= ready) {
+                return Ok(self.confirm(found));
+            }
+            if !unconfirmed.is_empty() {
+                actions.push(PoolAction::ConfirmScaleUp);
+            }
+            // Forget ScaleDown records for replicas which are being brought back,
+            // so the hook runs again the next time they're retired.
+            if status.scale_down_records.range(replicas..).next().is_some() {
+                actions.push(PoolAction::ForgetScaleDowns { from: replicas });
+            }
+            if !held.is_some_and(|x| x.contains(&replicas)) {
+                actions.push(PoolAction::AddLock(replicas));
+            }
+            actions.push(PoolAction::ScaleStatefulSet(replicas + 1));
+            return Ok(actions);
+        }
+
+        let ordinal = replicas - 1;
+        if held.map_or(true, |x| x.contains(&ordinal)) {
+            actions.push(PoolAction::RevokeLock(ordinal));
+        }
+        if status.scale_down_records.get(&ordinal) != Some(&gsp.spec.config_hash()) {
+            actions.push(PoolAction::RunScaleDown(ordinal));
+        }
+        // The nth replica can't become ready again without its lock,
+        // so once it's gone we can safely decrement the sts.
+        if ready < replicas {
+            actions.push(PoolAction::ScaleStatefulSet(ordinal));
+        }
+        Ok(actions)
+    }
+
+    // Ordinals of the sts replicas which haven't been confirmed by ScaleUp for the current config.
+    fn unconfirmed(&self, found: &StatefulSet) -> Vec<i32> {
+        let replicas = found.spec.as_ref().and_then(|x| x.replicas).unwrap_or(1);
+        let config_hash = self.pool.spec.config_hash();
+        let records = self
+            .pool
+            .status
+            .clone()
+            .map(|s| s.scale_up_records)
+            .unwrap_or_default();
+        (0..replicas)
+            .filter(|o| records.get(o) != Some(&config_hash))
+            .collect()
+    }
+
+    // Confirms the replicas via ScaleUp, if any can be: replicas are confirmed in order,
+    // once they're ready.
+    fn confirm(&self, found: &StatefulSet) -> Vec<PoolAction> {
+        match self.unconfirmed(found).first() {
+            Some(o) if *o < settled_ready(found) => vec![PoolAction::ConfirmScaleUp],
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{found_pool, gratefulset as gs};
+    use crate::gs::GratefulSetStatus;
+    use crate::gsp::{lock_data, GratefulSetPoolStatus};
+    use k8s_openapi::api::apps::v1::{StatefulSetSpec, StatefulSetStatus};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use serde_json::json;
+    use std::cmp::{max, min};
+
+    fn pool(name: &str, replicas: i32) -> PoolState {
+        PoolState {
+            name: String::from(name),
+            exists: true,
+            replicas,
+            observed_replicas: replicas,
+            ready: replicas,
+            unready_top: 0,
+        }
+    }
+
+    fn set(pool: &str, replicas: i32) -> Action {
+        Action::SetReplicas {
+            pool: String::from(pool),
+            replicas,
+        }
+    }
+
+    fn ready(current: &PoolState, old: &[PoolState]) -> i32 {
+        current.ready + old.iter().map(|p| p.ready).sum::<i32>()
+    }
+
+    // Carries out a planned action on the pools.
+    fn apply(a: &Action, current: &mut PoolState, old: &mut Vec<PoolState>) {
+        match a {
+            Action::CreatePool { pool, replicas } | Action::SetReplicas { pool, replicas } => {
+                let p = if *pool == current.name {
+                    &mut *current
+                } else {
+                    old.iter_mut().find(|p| p.name == *pool).unwrap()
+                };
+                p.exists = true;
+                p.replicas = *replicas;
+            }
+            Action::DeletePool { pool } => old.retain(|p| p.name != *pool),
+            a => panic!("unexpected {}", a),
+        }
+    }
+
+    // Runs the rollout to completion, settling every pool (all replicas ready) after each step.
+    // Returns the steps taken & the final pools.
+    fn simulate(
+        desired: i32,
+        budget: Budget,
+        mut current: PoolState,
+        mut old: Vec<PoolState>,
+    ) -> (Vec<Action>, PoolState, Vec<PoolState>) {
+        let mut steps = vec![];
+        let min_ready = min(desired - budget.max_unavailable, ready(&current, &old));
+        for _ in 0..1000 {
+            let actions = rollout(desired, budget, &current, &old);
+            if actions.is_empty() {
+                return (steps, current, old);
+            }
+            for a in actions {
+                apply(&a, &mut current, &mut old);
+                for p in old.iter_mut().chain(Some(&mut current)) {
+                    *p = PoolState {
+                        exists: p.exists,
+                        ..pool(&p.name, p.replicas)
+                    };
+                }
+                steps.push(a);
+                // at most max_unavailable replicas are unavailable at a time.
+                let ready = ready(&current, &old);
+                assert!(
+                    ready >= min_ready,
+                    "{} ready after {}",
+                    ready,
+                    steps.last().unwrap()
+                );
+            }
+        }
+        panic!("rollout didn't converge");
+    }
+
+    #[test]
+    fn scales_a_new_pool() {
+        let new = PoolState {
+            name: String::from("new"),
+            ..Default::default()
+        };
+        let (steps, current, old) = simulate(3, Budget::default(), new, vec![]);
+        assert_eq!(
+            steps,
+            vec![Action::CreatePool {
+                pool: String::from("new"),
+                replicas: 3
+            }]
+        );
+        assert_eq!(current.replicas, 3);
+        assert!(old.is_empty());
+    }
+
+    #[test]
+    fn retires_the_oldest_pool_first() {
+        let actions = rollout(
+            4,
+            Budget::default(),
+            &pool("new", 0),
+            &[pool("oldest", 2), pool("old", 2)],
+        );
+        assert_eq!(actions, vec![set("oldest", 1)]);
+    }
+
+    #[test]
+    fn retires_unready_old_replicas_first() {
+        // the unready replica is the highest ordinal: it goes without using up the budget.
+        let mut old = pool("old", 3);
+        old.ready = 2;
+        old.unready_top = 1;
+        assert_eq!(
+            rollout(3, Budget::default(), &pool("new", 0), &[old.clone()]),
+            vec![set("old", 2)]
+        );
+
+        // scaling down would remove a ready replica instead.
+        old.unready_top = 0;
+        assert_eq!(
+            rollout(3, Budget::default(), &pool("new", 0), &[old.clone()]),
+            vec![]
+        );
+        old.ready = 3;
+        old.replicas = 4;
+        old.observed_replicas = 4;
+        assert_eq!(
+            rollout(3, Budget::default(), &pool("new", 0), &[old]),
+            vec![set("old", 3)]
+        );
+    }
+
+    #[test]
+    fn migrates_one_replica_at_a_time() {
+        let new = PoolState {
+            name: String::from("new"),
+            ..Default::default()
+        };
+        let (steps, current, old) = simulate(3, Budget::default(), new, vec![pool("old", 3)]);
+        assert_eq!(
+            steps,
+            vec![
+                set("old", 2),
+                Action::CreatePool {
+                    pool: String::from("new"),
+                    replicas: 1
+                },
+                set("old", 1),
+                set("new", 2),
+                set("old", 0),
+                Action::DeletePool {
+                    pool: String::from("old")
+                },
+                set("new", 3),
+            ]
+        );
+        assert_eq!(current.replicas, 3);
+        assert!(old.is_empty());
+    }
+
+    #[test]
+    fn moves_several_replicas_per_step() {
+        let budget = Budget {
+            max_unavailable: 2,
+            max_surge: 0,
+        };
+        let new = PoolState {
+            name: String::from("new"),
+            ..Default::default()
+        };
+        let (steps, current, _) = simulate(4, budget, new, vec![pool("old", 4)]);
+        assert_eq!(
+            steps,
+            vec![
+                set("old", 2),
+                Action::CreatePool {
+                    pool: String::from("new"),
+                    replicas: 2
+                },
+                set("old", 0),
+                Action::DeletePool {
+                    pool: String::from("old")
+                },
+                set("new", 4),
+            ]
+        );
+        assert_eq!(current.replicas, 4);
+    }
+
+    #[test]
+    fn surges_new_replicas_first() {
+        let budget = Budget {
+            max_unavailable: 0,
+            max_surge: 2,
+        };
+        let (steps, current, _) = simulate(4, budget, pool("new", 0), vec![pool("old", 4)]);
+        assert_eq!(
+            steps,
+            vec![
+                set("new", 2),
+                set("old", 2),
+                set("new", 4),
+                set("old", 0),
+                Action::DeletePool {
+                    pool: String::from("old")
+                },
+            ]
+        );
+        assert_eq!(current.replicas, 4);
+    }
+
+    #[test]
+    fn waits_for_pools_to_settle() {
+        let mut old = pool("old", 2);
+        old.observed_replicas = 3;
+        assert_eq!(
+            rollout(3, Budget::default(), &pool("new", 1), &[old]),
+            vec![]
+        );
+
+        let mut new = pool("new", 1);
+        new.ready = 0;
+        assert_eq!(
+            rollout(3, Budget::default(), &new, &[pool("old", 2)]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn keeps_drained_pools_until_their_replicas_exit() {
+        let mut old = pool("old", 0);
+        old.observed_replicas = 1;
+        assert_eq!(
+            rollout(3, Budget::default(), &pool("new", 3), &[old]),
+            vec![]
+        );
+    }
+
+    // Rolls out random pools, settling them partially & at random between steps. Readiness is
+    // modeled per ordinal, as scaling a pool down removes its highest ordinals whether they're
+    // ready or not.
+    #[test]
+    fn rollout_properties() {
+        for seed in 1..=500 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let desired = rng.gen_range(0, 8);
+            let mut budget = Budget {
+                max_unavailable: rng.gen_range(0, 3),
+                max_surge: rng.gen_range(0, 3),
+            };
+            if budget
+                == (Budget {
+                    max_unavailable: 0,
+                    max_surge: 0,
+                })
+            {
+                budget = Budget::default();
+            }
+            let mut current = PoolState {
+                name: String::from("new"),
+                ..Default::default()
+            };
+            if rng.gen_bool(0.5) {
+                current = pool("new", rng.gen_range(0, 6));
+            }
+            // pool name -> readiness of each of its ordinals.
+            let mut ordinals: BTreeMap<String, Vec<bool>> = BTreeMap::new();
+            ordinals.insert(current.name.clone(), vec![true; current.replicas as usize]);
+            // Old pools may start with unready replicas, which never become ready when stuck.
+            // Stuck replicas at the top of their pool are retired, elsewhere they may hold up
+            // the rollout for good.
+            let stuck = rng.gen_range(0, 3);
+            let (stuck_top, stuck_anywhere) = (stuck == 1, stuck == 2);
+            let mut old: Vec<PoolState> = (0..rng.gen_range(0, 4))
+                .map(|i| {
+                    let p = pool(&format!("old{}", i), rng.gen_range(0, 6));
+                    let ready = rng.gen_range(0, p.replicas + 1) as usize;
+                    let mut x: Vec<bool> = (0..p.replicas).map(|_| rng.gen_bool(0.5)).collect();
+                    if stuck_top {
+                        x = (0..p.replicas as usize).map(|o| o < ready).collect();
+                    }
+                    ordinals.insert(p.name.clone(), x);
+                    p
+                })
+                .collect();
+            let is_stuck = |name: &str| (stuck_top || stuck_anywhere) && name.starts_with("old");
+
+            // Derives the planner's view of each pool from the readiness of its ordinals.
+            let observe = |p: &mut PoolState, x: &[bool]| {
+                p.ready = x.iter().filter(|r| **r).count() as i32;
+                p.unready_top = x.iter().rev().take_while(|r| !**r).count() as i32;
+            };
+            let ready = |ordinals: &BTreeMap<String, Vec<bool>>| -> i32 {
+                ordinals.values().flatten().filter(|r| **r).count() as i32
+            };
+            let requested = |current: &PoolState, old: &[PoolState]| {
+                current.replicas + old.iter().map(|p| p.replicas).sum::<i32>()
+            };
+            let min_ready = min(ready(&ordinals), max(desired - budget.max_unavailable, 0));
+            let max_requested = max(requested(&current, &old), desired + budget.max_surge);
+
+            let mut converged = false;
+            for _ in 0..1000 {
+                for p in old.iter_mut().chain(Some(&mut current)) {
+                    if rng.gen_bool(0.5) {
+                        p.observed_replicas = p.replicas;
+                    }
+                    // replicas become ready gradually, but go away at once, highest first.
+                    let x = ordinals.entry(p.name.clone()).or_default();
+                    x.resize(p.observed_replicas as usize, false);
+                    if !is_stuck(&p.name) && rng.gen_bool(0.5) {
+                        if let Some(o) = x.iter().position(|r| !*r) {
+                            x[o] = true;
+                        }
+                    }
+                    observe(p, x);
+                }
+                assert!(
+                    ready(&ordinals) >= min_ready,
+                    "seed {}: {} ready, want at least {}",
+                    seed,
+                    ready(&ordinals),
+                    min_ready
+                );
+
+                let actions = rollout(desired, budget, &current, &old);
+                let settled = current.settled() && old.iter().all(|p| p.settled());
+                if actions.is_empty() && settled && old.is_empty() && current.replicas == desired {
+                    converged = true;
+                    break;
+                }
+                let before = (current.clone(), old.clone());
+                for a in &actions {
+                    apply(a, &mut current, &mut old);
+                }
+                ordinals
+                    .retain(|name, _| *name == current.name || old.iter().any(|p| p.name == *name));
+                // No more replicas are requested than initially or desired (plus the surge).
+                assert!(requested(&current, &old) <= max_requested, "seed {}", seed);
+                // Replicas are only ever added to the current pool, up to the desired ones:
+                // lowering the replicas mid rollout doesn't create replicas to be removed later.
+                assert!(
+                    current.replicas <= max(before.0.replicas, desired),
+                    "seed {}",
+                    seed
+                );
+                for p in &old {
+                    let prev = before.1.iter().find(|x| x.name == p.name).unwrap();
+                    assert!(
+                        p.replicas <= prev.replicas,
+                        "seed {}: scaled up {}",
+                        seed,
+                        p.name
+                    );
+                }
+            }
+            assert!(
+                converged || stuck_anywhere,
+                "seed {}: rollout didn't converge",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn plans_a_new_gratefulset() {
+        let gs = gs(|_| {});
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &gs,
+                pools: &[]
+            }
+            .plan(),
+            vec![Action::CreatePool {
+                pool: Meta::name(&gs.pool()),
+                replicas: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn updates_the_current_pool_before_scaling_it() {
+        let old = gs(|_| {});
+        let new = gs(|x| {
+            x["replicas"] = json!(5);
+            x["template"]["spec"]["containers"][0]["image"] = json!("loki:2.1.0");
+        });
+        let pools = [found_pool(&old, 3, 0)];
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &new,
+                pools: &pools
+            }
+            .plan(),
+            vec![Action::UpdatePool {
+                pool: Meta::name(&pools[0])
+            }]
+        );
+
+        let pools = [found_pool(&new, 3, 0)];
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &new,
+                pools: &pools
+            }
+            .plan(),
+            vec![set(&Meta::name(&pools[0]), 5)]
+        );
+    }
+
+    #[test]
+    fn scales_down_before_rolling() {
+        let old = gs(|x| x["replicas"] = json!(5));
+        let new = gs(|x| {
+            x["replicas"] = json!(3);
+            x["template"]["spec"]["containers"][0]["image"] = json!("loki:2.1.0");
+        });
+        let plan = |pool: &GratefulSetPool| {
+            ObservedGratefulSet {
+                gs: &new,
+                pools: std::slice::from_ref(pool),
+            }
+            .plan()
+        };
+        let mut pool = found_pool(&old, 5, 0);
+        let name = Meta::name(&pool);
+        assert_eq!(plan(&pool), vec![set(&name, 3)]);
+
+        // the retired replicas haven't exited yet.
+        pool.spec.sts_spec.replicas = Some(3);
+        assert_eq!(plan(&pool), vec![]);
+
+        // only the remaining replicas are rolled.
+        let pool = found_pool(&old, 3, 0);
+        assert_eq!(plan(&pool), vec![Action::UpdatePool { pool: name }]);
+    }
+
+    #[test]
+    fn migrates_from_the_oldest_pool() {
+        let new = gs(|x| x["serviceName"] = json!("ingester-headless"));
+        let older = found_pool(&gs(|x| x["serviceName"] = json!("a")), 1, 0);
+        let old = found_pool(&gs(|_| {}), 2, 100);
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &new,
+                pools: &[old, older.clone()]
+            }
+            .plan(),
+            vec![set(&Meta::name(&older), 0)]
+        );
+    }
+
+    #[test]
+    fn pauses_migrations() {
+        let mut new = gs(|x| x["serviceName"] = json!("ingester-headless"));
+        new.spec.paused = true;
+        let old = found_pool(&gs(|_| {}), 3, 0);
+        let plan = |gs: &GratefulSet| {
+            ObservedGratefulSet {
+                gs,
+                pools: std::slice::from_ref(&old),
+            }
+            .plan()
+        };
+        assert_eq!(plan(&new), vec![]);
+
+        new.spec.paused = false;
+        assert_eq!(plan(&new), vec![set(&Meta::name(&old), 2)]);
+    }
+
+    #[test]
+    fn paused_gratefulsets_still_scale() {
+        let mut gs = gs(|x| x["replicas"] = json!(5));
+        gs.spec.paused = true;
+        let pool = found_pool(&gs, 3, 0);
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &gs,
+                pools: std::slice::from_ref(&pool)
+            }
+            .plan(),
+            vec![set(&Meta::name(&pool), 5)]
+        );
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &gs,
+                pools: &[]
+            }
+            .plan(),
+            vec![Action::CreatePool {
+                pool: Meta::name(&gs.pool()),
+                replicas: 5
+            }]
+        );
+
+        // the current pool is still updated in place.
+        let mut old = gs.clone();
+        old.spec.sts_spec.template.spec.as_mut().unwrap().containers[0].image =
+            Some(String::from("loki:1.6.0"));
+        let pool = found_pool(&old, 3, 0);
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &gs,
+                pools: std::slice::from_ref(&pool)
+            }
+            .plan(),
+            vec![Action::UpdatePool {
+                pool: Meta::name(&pool)
+            }]
+        );
+    }
+
+    #[test]
+    fn aborts_back_to_the_previous_pool() {
+        let mut new = gs(|x| x["serviceName"] = json!("ingester-headless"));
+        let mut old = found_pool(&gs(|_| {}), 2, 0);
+        let mut current = found_pool(&new, 1, 100);
+        new.spec.abort = true;
+        new.status = Some(GratefulSetStatus {
+            current_pool_hash: Some(current.hash()),
+            previous_pool_hash: Some(old.hash()),
+            ..Default::default()
+        });
+        let plan = |pools: &[GratefulSetPool]| ObservedGratefulSet { gs: &new, pools }.plan();
+
+        // replicas leave the new pool...
+        let pools = [old.clone(), current.clone()];
+        assert_eq!(plan(&pools), vec![set(&Meta::name(&current), 0)]);
+
+        // ...which is deleted once drained, while the previous pool is scaled back up as is.
+        current = found_pool(&new, 0, 100);
+        let pools = [old.clone(), current.clone()];
+        assert_eq!(
+            plan(&pools),
+            vec![
+                Action::DeletePool {
+                    pool: Meta::name(&current)
+                },
+                set(&Meta::name(&old), 3),
+            ]
+        );
+
+        // Nothing to do once rolled back, even with the spec still pointing at the new pool.
+        old = found_pool(&gs(|_| {}), 3, 0);
+        assert_eq!(plan(std::slice::from_ref(&old)), vec![]);
+    }
+
+    #[test]
+    fn grows_storage_in_place() {
+        let storage = |size: &str| {
+            let size = json!(size);
+            gs(move |x| {
+                x["volumeClaimTemplates"][0]["spec"]["resources"]["requests"]["storage"] =
+                    size.clone()
+            })
+        };
+        let plan = |gs: &GratefulSet, pool: &GratefulSetPool| {
+            ObservedGratefulSet {
+                gs,
+                pools: std::slice::from_ref(pool),
+            }
+            .plan()
+        };
+        let pool = found_pool(&storage("10Gi"), 3, 0);
+        assert_eq!(
+            plan(&storage("20Gi"), &pool),
+            vec![Action::UpdatePool {
+                pool: Meta::name(&pool)
+            }]
+        );
+
+        // a shrink migrates to a new pool...
+        let shrunk = storage("5Gi");
+        assert_ne!(Meta::name(&shrunk.pool()), Meta::name(&pool));
+        assert_eq!(plan(&shrunk, &pool), vec![set(&Meta::name(&pool), 2)]);
+
+        // ...even back to the size the grown pool is named after.
+        let mut grown = pool.clone();
+        grown.spec.sts_spec = storage("20Gi").spec.sts_spec;
+        assert_eq!(
+            plan(&storage("10Gi"), &grown),
+            vec![Action::BumpCollisionCount]
+        );
+    }
+
+    #[test]
+    fn bumps_the_collision_count() {
+        let gs = gs(|_| {});
+        // a pool of a different spec under the desired pool's name.
+        let mut taken = found_pool(&gs, 3, 0);
+        taken.spec.sts_spec.service_name = String::from("other");
+        assert_eq!(
+            ObservedGratefulSet {
+                gs: &gs,
+                pools: &[taken]
+            }
+            .plan(),
+            vec![Action::BumpCollisionCount]
+        );
+    }
+
+    // A simulated pool: the GratefulSetPool along with its statefulset, locks & pods.
+    struct World {
+        pool: GratefulSetPool,
+        sts: Option<StatefulSet>,
+        locks: Option<ConfigMap>,
+        // Replicas whose pod ran ScaleDown & exited.
+        exited: Vec<i32>,
+    }
+
+    impl World {
+        fn new(desired: i32) -> Self {
+            let gs = gs(|x| x["replicas"] = json!(desired));
+            World {
+                pool: gs.pool(),
+                sts: None,
+                locks: None,
+                exited: vec![],
+            }
+        }
+
+        // A world whose statefulset has `replicas` ready & confirmed replicas.
+        fn with_sts(desired: i32, replicas: i32) -> Self {
+            let mut w = World::new(desired);
+            w.apply(&PoolAction::CreateLocks);
+            w.apply(&PoolAction::AddLock(replicas - 1));
+            w.apply(&PoolAction::CreateStatefulSet { replicas });
+            w.settle(|| true);
+            w.apply(&PoolAction::ConfirmScaleUp);
+            w
+        }
+
+        fn plan(&self) -> Result<Vec<PoolAction>> {
+            ObservedPool {
+                pool: &self.pool,
+                sts: self.sts.as_ref(),
+                locks: self.locks.as_ref(),
+            }
+            .plan()
+        }
+
+        fn held(&self) -> Vec<i32> {
+            self.locks
+                .as_ref()
+                .map(|cm| held_locks(&cm.data.clone().unwrap_or_default()))
+                .unwrap_or_default()
+        }
+
+        // Whether every replica of the sts has been confirmed by ScaleUp.
+        fn confirmed(&self) -> bool {
+            let config_hash = self.pool.spec.config_hash();
+            let records = &self.pool.status.as_ref().unwrap().scale_up_records;
+            (0..self.replicas()).all(|o| records.get(&o) == Some(&config_hash))
+        }
+
+        fn status(&mut self) -> &mut GratefulSetPoolStatus {
+            self.pool.status.get_or_insert_with(Default::default)
+        }
+
+        fn replicas(&self) -> i32 {
+            self.sts
+                .as_ref()
+                .and_then(|x| x.spec.as_ref())
+                .and_then(|x| x.replicas)
+                .unwrap_or(0)
+        }
+
+        fn sts_status(&mut self) -> &mut StatefulSetStatus {
+            self.sts
+                .as_mut()
+                .unwrap()
+                .status
+                .get_or_insert_with(Default::default)
+        }
+
+        // Carries out an action like gsp::reconcile, checking the invariants of retiring replicas.
+        fn apply(&mut self, a: &PoolAction) {
+            let config_hash = self.pool.spec.config_hash();
+            match a {
+                PoolAction::CreateLocks => {
+                    let replicas = self.pool.spec.sts_spec.replicas.unwrap_or(1);
+                    self.locks = Some(ConfigMap {
+                        data: Some(lock_data(replicas)),
+                        ..Default::default()
+                    });
+                }
+                PoolAction::CreateStatefulSet { replicas } => {
+                    self.sts = Some(StatefulSet {
+                        spec: Some(StatefulSetSpec {
+                            replicas: Some(*replicas),
+                            ..self.pool.sts_spec()
+                        }),
+                        ..Default::default()
+                    });
+                }
+                PoolAction::ConfirmScaleUp => {
+                    let ready = settled_ready(self.sts.as_ref().unwrap());
+                    for o in 0..self.replicas() {
+                        let records = &mut self.status().scale_up_records;
+                        if records.get(&o) == Some(&config_hash) {
+                            continue;
+                        }
+                        if o >= ready {
+                            break;
+                        }
+                        records.insert(o, config_hash.clone());
+                    }
+                }
+                PoolAction::ForgetScaleDowns { from } => {
+                    self.status().scale_down_records.retain(|o, _| o < from);
+                }
+                PoolAction::AddLock(n) => {
+                    self.locks.as_mut().unwrap().data = Some(lock_data(n + 1));
+                }
+                PoolAction::RevokeLock(n) => {
+                    self.locks.as_mut().unwrap().data = Some(lock_data(*n));
+                }
+                PoolAction::RunScaleDown(n) => {
+                    assert!(
+                        !self.held().contains(n),
+                        "ScaleDown for locked replica {}",
+                        n
+                    );
+                    let status = self.status();
+                    status.scale_down_records.insert(*n, config_hash.clone());
+                    status.scale_up_records.remove(n);
+                    self.exited.push(*n);
+                }
+                PoolAction::ScaleStatefulSet(n) => {
+                    for o in *n..self.replicas() {
+                        assert!(!self.held().contains(&o), "retired locked replica {}", o);
+                        assert_eq!(
+                            self.status().scale_down_records.get(&o),
+                            Some(&config_hash),
+                            "retired replica {} without ScaleDown",
+                            o
+                        );
+                    }
+                    // pods above the new replicas are deleted.
+                    self.exited.retain(|o| o < n);
+                    self.sts.as_mut().unwrap().spec.as_mut().unwrap().replicas = Some(*n);
+                }
+                a => panic!("unexpected {}", a),
+            }
+        }
+
+        // Lets the statefulset controller catch up, as far as `progress` allows, and mirrors the
+        // sts status into the pool.
+        fn settle(&mut self, mut progress: impl FnMut() -> bool) {
+            if self.sts.is_none() {
+                return;
+            }
+            let replicas = self.replicas();
+            let held = self.held();
+            let exited = self.exited.clone();
+            let status = self.sts_status();
+            if progress() {
+                status.replicas = replicas;
+                status.current_replicas = Some(replicas);
+            }
+            // pods are ready while they hold their lock & haven't exited.
+            let up = (0..status.replicas)
+                .filter(|o| held.contains(o) && !exited.contains(o))
+                .count() as i32;
+            let ready = status.ready_replicas.unwrap_or(0);
+            status.ready_replicas = Some(if up > ready && !progress() { ready } else { up });
+            let sts_status = status.clone();
+            self.status().sts_status = sts_status;
+        }
+    }
+
+    #[test]
+    fn bootstraps_a_pool() {
+        let mut w = World::new(3);
+        assert_eq!(
+            w.plan().unwrap(),
+            vec![
+                PoolAction::CreateLocks,
+                PoolAction::CreateStatefulSet { replicas: 0 }
+            ]
+        );
+
+        // the replicas are then added one at a time.
+        w.apply(&PoolAction::CreateLocks);
+        w.apply(&PoolAction::CreateStatefulSet { replicas: 0 });
+        w.settle(|| true);
+        assert_eq!(w.plan().unwrap(), vec![PoolAction::ScaleStatefulSet(1)]);
+    }
+
+    #[test]
+    fn recreates_a_pool_at_its_observed_replicas() {
+        let mut w = World::with_sts(3, 2);
+        w.settle(|| true);
+        w.sts = None;
+        assert_eq!(
+            w.plan().unwrap(),
+            vec![PoolAction::CreateStatefulSet { replicas: 2 }]
+        );
+    }
+
+    #[test]
+    fn scales_up_one_confirmed_replica_at_a_time() {
+        let mut w = World::with_sts(3, 1);
+        w.pool.spec.sts_spec.replicas = Some(3);
+        assert_eq!(
+            w.plan().unwrap(),
+            vec![PoolAction::AddLock(1), PoolAction::ScaleStatefulSet(2)]
+        );
+
+        // the new replica isn't ready yet, so it can't be confirmed.
+        w.apply(&PoolAction::AddLock(1));
+        w.apply(&PoolAction::ScaleStatefulSet(2));
+        w.settle(|| false);
+        w.sts_status().current_replicas = Some(2);
+        assert_eq!(w.plan().unwrap(), vec![]);
+
+        w.settle(|| true);
+        assert_eq!(
+            w.plan().unwrap(),
+            vec![
+                PoolAction::ConfirmScaleUp,
+                PoolAction::AddLock(2),
+                PoolAction::ScaleStatefulSet(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn retires_replicas_after_scale_down() {
+        let mut w = World::with_sts(2, 3);
+        w.pool.spec.sts_spec.replicas = Some(2);
+        assert_eq!(
+            w.plan().unwrap(),
+            vec![PoolAction::RevokeLock(2), PoolAction::RunScaleDown(2)]
+        );
+        w.apply(&PoolAction::RevokeLock(2));
+        w.apply(&PoolAction::RunScaleDown(2));
+        w.settle(|| true);
+        assert_eq!(w.plan().unwrap(), vec![PoolAction::ScaleStatefulSet(2)]);
+    }
+
+    #[test]
+    fn rolls_template_changes() {
+        let mut w = World::with_sts(3, 3);
+        w.pool
+            .spec
+            .sts_spec
+            .template
+            .spec
+            .as_mut()
+            .unwrap()
+            .containers[0]
+            .image = Some(String::from("loki:2.1.0"));
+        assert_eq!(w.plan().unwrap(), vec![PoolAction::UpdateStatefulSet]);
+    }
+
+    #[test]
+    fn recreates_statefulsets_with_changed_immutable_fields() {
+        let mut w = World::with_sts(3, 3);
+        w.pool.spec.sts_spec.service_name = String::from("ingester-headless");
+        assert!(w.plan().is_err());
+
+        w.pool.spec.migration_strategy = Some(MigrationStrategy::Recreate);
+        assert_eq!(w.plan().unwrap(), vec![PoolAction::RecreateStatefulSet]);
+    }
+
+    // Scales random pools, with the statefulset controller catching up at random.
+    #[test]
+    fn pool_properties() {
+        for seed in 1..=500 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let desired = rng.gen_range(0, 6);
+            let mut w = if rng.gen_bool(0.5) {
+                World::new(desired)
+            } else {
+                World::with_sts(desired, rng.gen_range(0, 6))
+            };
+
+            let mut converged = false;
+            for _ in 0..1000 {
+                w.settle(|| rng.gen_bool(0.5));
+                let actions = w.plan().unwrap();
+                if actions.is_empty()
+                    && w.replicas() == desired
+                    && w.pool.status.as_ref().unwrap().sts_status.ready_replicas == Some(desired)
+                    && w.held() == (0..desired).collect::<Vec<_>>()
+                    && w.pool.ready_replicas() == desired
+                {
+                    converged = true;
+                    break;
+                }
+                for a in &actions {
+                    w.apply(a);
+                    // gsp::reconcile stops unless ScaleUp confirmed every replica.
+                    if *a == PoolAction::ConfirmScaleUp && !w.confirmed() {
+                        break;
+                    }
+                }
+            }
+            assert!(converged, "seed {}: pool didn't converge", seed);
+        }
+    }
+}
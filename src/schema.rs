@@ -0,0 +1,405 @@
+use crate::errors::*;
+use k8s_openapi::api::apps::v1::StatefulSetSpec;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::JSONSchemaProps;
+use serde::de::{self, DeserializeOwned, Deserializer, Expected, IntoDeserializer, Visitor};
+use serde_json::{json, Map, Value};
+
+// Structural OpenAPI v3 schemas for the CRDs.
+//
+// The operator's own fields are written out below. The embedded StatefulSetSpec, including
+// the pod template & volume claims, is derived from the k8s-openapi types, so type errors
+// anywhere in it are caught at admission time rather than when the pool creates its sts.
+// Unknown (e.g. misspelled) fields are pruned by the API server rather than rejected.
+
+fn object(properties: Value, required: &[&str]) -> Value {
+    let mut x = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        x["required"] = json!(required);
+    }
+    x
+}
+
+fn preserved() -> Value {
+    json!({ "type": "object", "x-kubernetes-preserve-unknown-fields": true })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+// An absolute number or a percentage, e.g. `2` or `25%`.
+fn int_or_percent() -> Value {
+    json!({
+        "x-kubernetes-int-or-string": true,
+        "anyOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^[0-9]+%$" },
+        ],
+    })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn sts_spec() -> Result<Value> {
+    let mut x = typed::<StatefulSetSpec>()?;
+    let fields = &mut x["properties"];
+    fields["podManagementPolicy"]["enum"] = json!(["OrderedReady", "Parallel"]);
+    fields["replicas"]["minimum"] = json!(0);
+    fields["updateStrategy"]["properties"]["type"]["enum"] = json!(["OnDelete", "RollingUpdate"]);
+    Ok(x)
+}
+
+fn http_scale_down() -> Value {
+    object(
+        json!({
+            "path": string(),
+            "port": { "type": "integer", "format": "int32", "minimum": 1, "maximum": 65535 },
+            "method": string(),
+            "expectedStatusCodes": array(json!({ "type": "integer", "minimum": 100, "maximum": 599 })),
+            "timeoutSeconds": { "type": "integer", "format": "int64", "minimum": 1 },
+            "retries": { "type": "integer", "format": "int32", "minimum": 0 },
+        }),
+        &["path", "port"],
+    )
+}
+
+fn root(spec: Value) -> Result<JSONSchemaProps> {
+    let x = object(
+        json!({
+            "apiVersion": string(),
+            "kind": string(),
+            "metadata": { "type": "object" },
+            "spec": spec,
+            "status": preserved(),
+        }),
+        &["spec"],
+    );
+    Ok(serde_json::from_value(x)?)
+}
+
+/// Schema of the GratefulSet CRD.
+pub fn gratefulset() -> Result<JSONSchemaProps> {
+    root(object(
+        json!({
+            "name": { "type": "string", "minLength": 1 },
+            "stsSpec": sts_spec()?,
+            "scaleDown": http_scale_down(),
+            "immutableFields": array(string()),
+            "migrationStrategy": { "type": "string", "enum": ["PoolMigration", "Recreate"] },
+            "rolloutStrategy": object(
+                json!({
+                    "maxUnavailable": int_or_percent(),
+                    "maxSurge": int_or_percent(),
+                }),
+                &[],
+            ),
+            "paused": { "type": "boolean" },
+            "abort": { "type": "boolean" },
+        }),
+        &["name", "stsSpec"],
+    ))
+}
+
+/// Schema of the GratefulSetPool CRD.
+pub fn gratefulsetpool() -> Result<JSONSchemaProps> {
+    root(object(
+        json!({
+            "name": { "type": "string", "minLength": 1 },
+            "stsSpec": sts_spec()?,
+            "scaleDown": http_scale_down(),
+            "immutableFields": array(string()),
+            "migrationStrategy": { "type": "string", "enum": ["PoolMigration", "Recreate"] },
+        }),
+        &["name", "stsSpec"],
+    ))
+}
+
+// Derives the schema of a k8s-openapi type from its Deserialize impl: it's deserialized from a
+// Tracer, which offers every field of every struct, records the shape of the value each field
+// asks for and hands back a placeholder of that shape.
+fn typed<T: DeserializeOwned>() -> Result<Value> {
+    let (mut schema, mut optional) = (Value::Null, false);
+    T::deserialize(Tracer {
+        schema: &mut schema,
+        optional: &mut optional,
+    })
+    .map_err(|e| {
+        format!(
+            "deriving the schema of {}: {}",
+            std::any::type_name::<T>(),
+            e
+        )
+    })?;
+    Ok(schema)
+}
+
+fn int_or_string() -> Value {
+    json!({
+        "x-kubernetes-int-or-string": true,
+        "anyOf": [{ "type": "integer" }, { "type": "string" }],
+    })
+}
+
+type Traced<T> = std::result::Result<T, de::value::Error>;
+
+struct Tracer<'a> {
+    schema: &'a mut Value,
+    // Set when the value is an Option, i.e. the field isn't required.
+    optional: &'a mut bool,
+}
+
+impl<'de, 'a> Deserializer<'de> for Tracer<'a> {
+    type Error = de::value::Error;
+
+    // IntOrString is the only self describing type embedded in a StatefulSetSpec.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        let expected = format!("{}", &visitor as &dyn Expected);
+        if expected != "IntOrString" {
+            return Err(de::Error::custom(format!(
+                "can't derive a schema for {}",
+                expected
+            )));
+        }
+        *self.schema = int_or_string();
+        visitor.visit_i32(0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.schema = json!({ "type": "boolean" });
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.schema = json!({ "type": "integer", "format": "int32" });
+        visitor.visit_i32(0)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.schema = json!({ "type": "integer", "format": "int64" });
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.schema = json!({ "type": "number", "format": "double" });
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.schema = string();
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        *self.optional = true;
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Traced<V::Value> {
+        let placeholder = match name {
+            "Quantity" => {
+                *self.schema = int_or_string();
+                "0"
+            }
+            "Time" | "MicroTime" => {
+                *self.schema = json!({ "type": "string", "format": "date-time" });
+                "1970-01-01T00:00:00Z"
+            }
+            "FieldsV1" => {
+                *self.schema = preserved();
+                ""
+            }
+            _ => return visitor.visit_newtype_struct(self),
+        };
+        visitor.visit_newtype_struct(placeholder.into_deserializer())
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        let (mut items, mut optional) = (Value::Null, false);
+        let x = visitor.visit_seq(Element(Some(Tracer {
+            schema: &mut items,
+            optional: &mut optional,
+        })))?;
+        *self.schema = json!({ "type": "array", "items": items });
+        Ok(x)
+    }
+
+    // Maps are traced as a struct with a single, empty, key.
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Traced<V::Value> {
+        let mut fields = Fields::new(&[""]);
+        let x = visitor.visit_map(&mut fields)?;
+        let values = fields.properties.remove("").unwrap_or_default();
+        *self.schema = json!({ "type": "object", "additionalProperties": values });
+        Ok(x)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Traced<V::Value> {
+        // Embedded resources (e.g. claims, unlike owner references) check their typemeta
+        // against their own, so it's left out of the trace.
+        let resource = ["apiVersion", "kind", "metadata"]
+            .iter()
+            .all(|f| fields.contains(f));
+        let (typemeta, traced): (Vec<&str>, Vec<&str>) = fields
+            .iter()
+            .partition(|f| resource && (**f == "apiVersion" || **f == "kind"));
+        let mut fields = Fields::new(&traced);
+        let x = visitor.visit_map(&mut fields)?;
+        let mut properties: Map<String, Value> =
+            typemeta.iter().map(|f| (f.to_string(), string())).collect();
+        properties.append(&mut fields.properties);
+        *self.schema = object(Value::Object(properties), &fields.required);
+        Ok(x)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 u8 u16 u32 u64 f32 char bytes byte_buf unit unit_struct tuple tuple_struct enum
+        identifier ignored_any
+    }
+}
+
+// Offers each of `keys` once, tracing the value asked for into `properties`.
+struct Fields<'k> {
+    keys: std::slice::Iter<'k, &'k str>,
+    current: &'k str,
+    properties: Map<String, Value>,
+    required: Vec<&'k str>,
+}
+
+impl<'k> Fields<'k> {
+    fn new(keys: &'k [&'k str]) -> Self {
+        Fields {
+            keys: keys.iter(),
+            current: "",
+            properties: Map::new(),
+            required: vec![],
+        }
+    }
+}
+
+impl<'de, 'k> de::MapAccess<'de> for &mut Fields<'k> {
+    type Error = de::value::Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Traced<Option<K::Value>> {
+        match self.keys.next() {
+            Some(key) => {
+                self.current = key;
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Traced<V::Value> {
+        let (mut schema, mut optional) = (Value::Null, false);
+        let x = seed.deserialize(Tracer {
+            schema: &mut schema,
+            optional: &mut optional,
+        })?;
+        self.properties.insert(self.current.to_string(), schema);
+        if !optional {
+            self.required.push(self.current);
+        }
+        Ok(x)
+    }
+}
+
+// A sequence of a single traced element.
+struct Element<'a>(Option<Tracer<'a>>);
+
+impl<'de, 'a> de::SeqAccess<'de> for Element<'a> {
+    type Error = de::value::Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Traced<Option<T::Value>> {
+        self.0.take().map(|t| seed.deserialize(t)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    // Paths in `x` which `schema` would prune as unknown fields.
+    fn unknown(schema: &Value, x: &Value, path: &str, found: &mut Vec<String>) {
+        if schema["x-kubernetes-preserve-unknown-fields"] == json!(true) {
+            return;
+        }
+        match x {
+            Value::Object(fields) => {
+                for (k, v) in fields {
+                    let path = format!("{}.{}", path, k);
+                    match schema["properties"]
+                        .get(k)
+                        .or_else(|| schema.get("additionalProperties"))
+                    {
+                        Some(field) => unknown(field, v, &path, found),
+                        None => found.push(path),
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for (i, v) in items.iter().enumerate() {
+                    unknown(&schema["items"], v, &format!("{}[{}]", path, i), found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Every node is typed, as structural schemas require.
+    fn untyped(schema: &Value, path: &str, found: &mut Vec<String>) {
+        if schema.get("type").is_none() && schema.get("x-kubernetes-int-or-string").is_none() {
+            found.push(String::from(path));
+        }
+        for (k, v) in schema["properties"].as_object().into_iter().flatten() {
+            untyped(v, &format!("{}.{}", path, k), found);
+        }
+        for k in &["items", "additionalProperties"] {
+            if let Some(v) = schema.get(k) {
+                untyped(v, &format!("{}.{}", path, k), found);
+            }
+        }
+    }
+
+    #[test]
+    fn types_the_embedded_statefulset_spec() {
+        let schema = sts_spec().unwrap();
+        let mut found = vec![];
+        untyped(&schema, "stsSpec", &mut found);
+        assert_eq!(found, Vec::<String>::new());
+
+        let mut spec = fixtures::sts_json();
+        spec["template"]["spec"]["containers"][0]["imagePullPolcy"] = json!("Always");
+        spec["volumeClaimTemplates"][0]["spec"]["storageClasName"] = json!("ssd");
+        let mut found = vec![];
+        unknown(&schema, &spec, "stsSpec", &mut found);
+        assert_eq!(
+            found,
+            vec![
+                "stsSpec.template.spec.containers[0].imagePullPolcy",
+                "stsSpec.volumeClaimTemplates[0].spec.storageClasName",
+            ]
+        );
+
+        let container = &schema["properties"]["template"]["properties"]["spec"]["properties"]
+            ["containers"]["items"];
+        assert_eq!(container["required"], json!(["name"]));
+    }
+}
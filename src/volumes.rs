@@ -0,0 +1,273 @@
+use crate::errors::*;
+use crate::gsp::{GratefulSetPool, ImmutableSts};
+use k8s_openapi::api::apps::v1::StatefulSetSpec;
+use k8s_openapi::api::core::v1::PersistentVolumeClaim;
+use k8s_openapi::api::storage::v1::StorageClass;
+use kube::api::{ListParams, Meta, PatchParams};
+use kube::{Api, Client};
+use log::info;
+use std::collections::BTreeMap;
+
+// Volume expansion: growing the storage requested by the volumeClaimTemplates of a pool.
+//
+// The claim templates of a statefulset are immutable, but the PVCs created from them can be
+// grown in place when their StorageClass allows volume expansion. Rather than migrating every
+// replica to a new pool, the existing PVCs are patched and the statefulset is recreated with the
+// larger templates, orphaning (& then re-adopting) its pods.
+
+/// Storage requested by each volumeClaimTemplate, by claim name.
+pub fn storage_requests(spec: &StatefulSetSpec) -> BTreeMap<String, String> {
+    spec.volume_claim_templates
+        .iter()
+        .flatten()
+        .filter_map(|claim| {
+            let storage = claim
+                .spec
+                .as_ref()?
+                .resources
+                .as_ref()?
+                .requests
+                .as_ref()?
+                .get("storage")?;
+            Some((claim.metadata.name.clone()?, storage.0.clone()))
+        })
+        .collect()
+}
+
+/// Whether `desired` requests less storage than `found` for any claim,
+/// which can't be done in place.
+pub fn shrinks(desired: &StatefulSetSpec, found: &StatefulSetSpec) -> bool {
+    let found = storage_requests(found);
+    storage_requests(desired).iter().any(|(name, want)| {
+        match (
+            parse_quantity(want),
+            found.get(name).and_then(|x| parse_quantity(x)),
+        ) {
+            (Some(want), Some(have)) => want < have,
+            _ => false,
+        }
+    })
+}
+
+/// The claims to grow (claim name -> desired storage) when `desired` only differs from
+/// `found` in immutable fields by growing storage requests. None if the specs differ otherwise.
+pub fn expansion(
+    desired: &StatefulSetSpec,
+    found: &StatefulSetSpec,
+) -> Option<BTreeMap<String, String>> {
+    if ImmutableSts(desired, &[]).sans_storage() != ImmutableSts(found, &[]).sans_storage()
+        || shrinks(desired, found)
+    {
+        return None;
+    }
+    let found = storage_requests(found);
+    let grown: BTreeMap<String, String> = storage_requests(desired)
+        .into_iter()
+        .filter(|(name, want)| found.get(name) != Some(want))
+        .collect();
+    Some(grown).filter(|x| !x.is_empty())
+}
+
+/// Grows the PVCs of the pool to the storage in `grown`.
+/// Every ordinal's PVCs are grown, including those left behind by a scale down, so replicas
+/// which are brought back get the new size too.
+pub async fn expand(
+    client: Client,
+    gsp: &GratefulSetPool,
+    grown: &BTreeMap<String, String>,
+) -> Result<()> {
+    let ns = Meta::namespace(gsp).expect("gsp is namespaced");
+    let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), &ns);
+    let classes: Api<StorageClass> = Api::all(client);
+
+    // The statefulset controller labels PVCs with its selector's matchLabels.
+    let selector = gsp
+        .spec
+        .sts_spec
+        .selector
+        .match_labels
+        .iter()
+        .flatten()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(",");
+    let found = pvcs
+        .list(&ListParams::default().labels(&selector))
+        .await
+        .map_err(|e| Error::with_chain(e, format!("listing pvcs of {}", Meta::name(gsp))))?
+        .items;
+
+    for (name, class, storage) in to_grow(&Meta::name(gsp), &found, grown) {
+        if let Some(class) = class {
+            let expandable = classes
+                .get(&class)
+                .await
+                .map_err(|e| Error::with_chain(e, format!("getting storageclass {}", class)))?
+                .allow_volume_expansion
+                .unwrap_or(false);
+            if !expandable {
+                return Err(Error::from(format!(
+                    "can't grow pvc {}: storageclass {} doesn't allow volume expansion",
+                    name, class
+                )));
+            }
+        }
+
+        info!("growing pvc {} to {}", name, storage);
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "spec": { "resources": { "requests": { "storage": storage } } }
+        }))?;
+        pvcs.patch(&name, &PatchParams::default(), patch)
+            .await
+            .map_err(|e| Error::with_chain(e, format!("growing pvc {}", name)))?;
+    }
+    Ok(())
+}
+
+// The PVCs of `pool` among `pvcs` requesting less storage than `grown`, as
+// (name, storage class, desired storage). The selector shared with other pools matches their
+// PVCs too, so they're told apart by name: <claim>-<sts>-<ordinal>, as the statefulset
+// controller names them.
+fn to_grow(
+    pool: &str,
+    pvcs: &[PersistentVolumeClaim],
+    grown: &BTreeMap<String, String>,
+) -> Vec<(String, Option<String>, String)> {
+    let mut out = vec![];
+    for (claim, storage) in grown {
+        let prefix = format!("{}-{}-", claim, pool);
+        for pvc in pvcs {
+            let name = Meta::name(pvc);
+            let ordinal = name.strip_prefix(&prefix).map(str::parse::<u32>);
+            if !matches!(ordinal, Some(Ok(_))) {
+                continue;
+            }
+            let spec = pvc.spec.clone().unwrap_or_default();
+            let current = spec
+                .resources
+                .and_then(|r| r.requests)
+                .and_then(|r| r.get("storage").map(|q| q.0.clone()));
+            if current.as_deref().and_then(parse_quantity) >= parse_quantity(storage) {
+                continue;
+            }
+            out.push((name, spec.storage_class_name, storage.clone()));
+        }
+    }
+    out
+}
+
+/// Parses a resource quantity (e.g. `10Gi`, `500M`, `1e3`) into its value.
+pub fn parse_quantity(q: &str) -> Option<f64> {
+    let q = q.trim();
+    let suffixes: &[(&str, f64)] = &[
+        ("Ki", 1024f64),
+        ("Mi", 1024f64.powi(2)),
+        ("Gi", 1024f64.powi(3)),
+        ("Ti", 1024f64.powi(4)),
+        ("Pi", 1024f64.powi(5)),
+        ("Ei", 1024f64.powi(6)),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+    ];
+    for (suffix, scale) in suffixes {
+        if let Some(n) = q.strip_suffix(suffix) {
+            return n.parse::<f64>().ok().map(|n| n * scale);
+        }
+    }
+    // plain numbers, including exponents like 1e3
+    q.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    fn spec(storage: &str, class: &str) -> StatefulSetSpec {
+        fixtures::sts_spec(|x| {
+            let claim = &mut x["volumeClaimTemplates"][0]["spec"];
+            claim["storageClassName"] = json!(class);
+            claim["resources"]["requests"]["storage"] = json!(storage);
+        })
+    }
+
+    #[test]
+    fn expansion_only_grows_storage() {
+        let grown: BTreeMap<String, String> = vec![(String::from("data"), String::from("1Ti"))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            expansion(&spec("1Ti", "ssd"), &spec("500Gi", "ssd")),
+            Some(grown)
+        );
+        assert_eq!(
+            expansion(&spec("500Gi", "ssd"), &spec("500Gi", "ssd")),
+            None
+        );
+        assert_eq!(
+            expansion(&spec("100Gi", "ssd"), &spec("500Gi", "ssd")),
+            None
+        );
+        assert_eq!(expansion(&spec("1Ti", "hdd"), &spec("500Gi", "ssd")), None);
+        assert!(shrinks(&spec("100Gi", "ssd"), &spec("500Gi", "ssd")));
+    }
+
+    #[test]
+    fn grows_claims_left_by_a_scale_down() {
+        let pvc = |name: &str, storage: &str| -> PersistentVolumeClaim {
+            serde_json::from_value(json!({
+                "metadata": { "name": name },
+                "spec": {
+                    "storageClassName": "ssd",
+                    "resources": { "requests": { "storage": storage } },
+                },
+            }))
+            .unwrap()
+        };
+        // The pool was scaled down from 3 to 1 replicas before its storage was grown: the PVCs
+        // of replicas 1 & 2 are still around, and are grown for when it's scaled back up.
+        let pvcs = vec![
+            pvc("data-ingester-abc-0", "10Gi"),
+            pvc("data-ingester-abc-1", "10Gi"),
+            pvc("data-ingester-abc-2", "10Gi"),
+            // already grown.
+            pvc("data-ingester-abc-3", "20Gi"),
+            // another pool's, matching the same selector.
+            pvc("data-ingester-abcd-0", "10Gi"),
+            pvc("data-ingester-def-0", "10Gi"),
+        ];
+        let grown: BTreeMap<String, String> = vec![(String::from("data"), String::from("20Gi"))]
+            .into_iter()
+            .collect();
+        let names: Vec<String> = to_grow("ingester-abc", &pvcs, &grown)
+            .into_iter()
+            .map(|(name, class, storage)| {
+                assert_eq!((class.as_deref(), storage.as_str()), (Some("ssd"), "20Gi"));
+                name
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "data-ingester-abc-0",
+                "data-ingester-abc-1",
+                "data-ingester-abc-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn quantities() {
+        assert_eq!(parse_quantity("1Ki"), Some(1024.0));
+        assert_eq!(parse_quantity("1.5G"), Some(1.5e9));
+        assert_eq!(parse_quantity("1e3"), Some(1000.0));
+        assert_eq!(parse_quantity("250m"), Some(0.25));
+        assert_eq!(parse_quantity("lots"), None);
+    }
+}
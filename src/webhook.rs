@@ -0,0 +1,604 @@
+use crate::diff;
+use crate::errors::*;
+use crate::gs::GratefulSet;
+use crate::gsp::MigrationStrategy;
+use crate::volumes;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::Meta;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+// The statefulset controller labels pods with `controller-revision-hash=<sts>-<hash>`,
+// which must fit in a 63 character label value. This leaves 52 characters for the sts name.
+const MAX_STS_NAME: usize = 52;
+// pod names double as hostnames, which are limited to a 63 character DNS-1123 label.
+const MAX_POD_NAME: usize = 63;
+
+/// AdmissionReview is the (subset of the) admission.k8s.io/v1 payload exchanged with the API server.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<AdmissionRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<AdmissionResponse>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionRequest {
+    pub uid: String,
+    pub operation: String,
+    #[serde(default)]
+    pub object: Option<serde_json::Value>,
+    #[serde(default)]
+    pub old_object: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionResponse {
+    pub uid: String,
+    pub allowed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<AdmissionStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AdmissionStatus {
+    pub code: u16,
+    pub message: String,
+}
+
+/// The outcome of validating a GratefulSet.
+#[derive(Clone, Debug, Default)]
+pub struct Validation {
+    /// Reasons the object is rejected.
+    pub errors: Vec<String>,
+    /// Surfaced to the user (e.g. by kubectl), but don't prevent admission.
+    pub warnings: Vec<String>,
+}
+
+/// Validates a GratefulSet, comparing against the previous version on updates.
+pub fn validate(gs: &GratefulSet, old: Option<&GratefulSet>) -> Validation {
+    let mut v = Validation::default();
+    let spec = &gs.spec;
+
+    if !is_dns1123_label(&spec.name) {
+        v.errors.push(format!(
+            "spec.name {:?} must be a DNS-1123 label: lowercase alphanumerics or '-', starting & ending with an alphanumeric",
+            spec.name
+        ));
+    }
+
+    let replicas = spec.sts_spec.replicas.unwrap_or(1);
+    if replicas < 0 {
+        v.errors.push(format!(
+            "spec.stsSpec.replicas must not be negative, got {}",
+            replicas
+        ));
+    }
+
+    let sts_name = Meta::name(&gs.pool());
+    if sts_name.len() > MAX_STS_NAME {
+        v.errors.push(format!(
+            "spec.name {:?} is too long: pool name {:?} exceeds {} characters",
+            spec.name, sts_name, MAX_STS_NAME
+        ));
+    }
+    let pod_name = format!("{}-{}", sts_name, std::cmp::max(replicas - 1, 0));
+    if pod_name.len() > MAX_POD_NAME {
+        v.errors.push(format!(
+            "spec.name {:?} is too long: pod name {:?} exceeds {} characters",
+            spec.name, pod_name, MAX_POD_NAME
+        ));
+    }
+
+    let template_labels = spec
+        .sts_spec
+        .template
+        .metadata
+        .as_ref()
+        .and_then(|md| md.labels.clone())
+        .unwrap_or_default();
+    if !selector_matches(&spec.sts_spec.selector, &template_labels) {
+        v.errors.push(String::from(
+            "spec.stsSpec.selector does not match spec.stsSpec.template.metadata.labels",
+        ));
+    }
+
+    // Paths which aren't set (yet) are fine: they're immutable while unset.
+    for path in &spec.immutable_fields {
+        if let Err(e) = diff::parse_path(path) {
+            v.errors.push(format!("spec.immutableFields: {}", e));
+        }
+    }
+
+    if let Some(strategy) = &spec.rollout_strategy {
+        if let Err(e) = strategy.budget(replicas) {
+            v.errors.push(format!("spec.rolloutStrategy: {}", e));
+        }
+    }
+
+    if spec.abort
+        && gs
+            .status
+            .as_ref()
+            .and_then(|x| x.previous_pool_hash.as_ref())
+            .is_none()
+    {
+        v.warnings.push(String::from(
+            "spec.abort is set but there's no previous pool to roll back to: nothing will move until it's unset",
+        ));
+    }
+
+    if let Some(old) = old {
+        // Adopted pods keep the DNS subdomain of the service they were created with, so they'd
+        // no longer be reachable (e.g. by the HTTP ScaleDown) under the new one.
+        if spec.migration_strategy == Some(MigrationStrategy::Recreate)
+            && spec.sts_spec.service_name != old.spec.sts_spec.service_name
+        {
+            v.errors.push(String::from(
+                "spec.stsSpec.serviceName can't be changed with the Recreate migrationStrategy, as the adopted pods keep their DNS subdomain: use PoolMigration to move them to the new service",
+            ));
+        }
+
+        let (old_pool, new_pool) = (old.pool(), gs.pool());
+        if !old_pool.spec.adopts(&new_pool.spec) {
+            let (old_hash, new_hash) = (old_pool.hash(), new_pool.hash());
+            v.warnings.push(
+                if volumes::shrinks(&spec.sts_spec, &old.spec.sts_spec) {
+                    format!(
+                        "this change shrinks the storage requested by spec.stsSpec.volumeClaimTemplates, which can't be done in place, and will migrate all {} replicas from pool {} to a new pool {}",
+                        replicas, old_hash, new_hash
+                    )
+                } else if spec.migration_strategy == Some(MigrationStrategy::Recreate)
+                    && spec.sts_spec.selector == old.spec.sts_spec.selector
+                {
+                    String::from("this change modifies immutable statefulset fields and will recreate the statefulset, orphaning its pods")
+                } else {
+                    format!(
+                        "this change modifies immutable statefulset fields and will migrate all {} replicas from pool {} to a new pool {}",
+                        replicas, old_hash, new_hash
+                    )
+                },
+            );
+        }
+    }
+    v
+}
+
+// Whether `s` is a valid DNS-1123 label, as required for names used in hostnames.
+fn is_dns1123_label(s: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    !s.is_empty()
+        && s.len() <= MAX_POD_NAME
+        && s.chars().all(|c| valid_char(c) || c == '-')
+        && s.starts_with(valid_char)
+        && s.ends_with(valid_char)
+}
+
+// Whether a label selector selects the given labels. An empty selector selects nothing,
+// mirroring the statefulset validation.
+fn selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector.match_labels.clone().unwrap_or_default();
+    let match_expressions = selector.match_expressions.clone().unwrap_or_default();
+    if match_labels.is_empty() && match_expressions.is_empty() {
+        return false;
+    }
+
+    match_labels.iter().all(|(k, v)| labels.get(k) == Some(v))
+        && match_expressions.iter().all(|expr| {
+            let values = expr.values.clone().unwrap_or_default();
+            match expr.operator.as_str() {
+                "In" => labels.get(&expr.key).is_some_and(|v| values.contains(v)),
+                "NotIn" => labels.get(&expr.key).map_or(true, |v| !values.contains(v)),
+                "Exists" => labels.contains_key(&expr.key),
+                "DoesNotExist" => !labels.contains_key(&expr.key),
+                _ => false,
+            }
+        })
+}
+
+impl AdmissionReview {
+    // Builds the response to this review via `admit`.
+    fn respond<F>(self, admit: F) -> AdmissionReview
+    where
+        F: FnOnce(&AdmissionRequest) -> Result<AdmissionResponse>,
+    {
+        let req = self.request.unwrap_or_default();
+        let response = admit(&req).unwrap_or_else(|e| AdmissionResponse {
+            uid: req.uid.clone(),
+            allowed: false,
+            status: Some(AdmissionStatus {
+                code: 400,
+                message: e.to_string(),
+            }),
+            ..Default::default()
+        });
+        AdmissionReview {
+            api_version: self.api_version,
+            kind: self.kind,
+            request: None,
+            response: Some(response),
+        }
+    }
+}
+
+fn validate_request(req: &AdmissionRequest) -> Result<AdmissionResponse> {
+    let gs: GratefulSet = match &req.object {
+        Some(obj) => serde_json::from_value(obj.clone())?,
+        // deletes carry no object
+        None => {
+            return Ok(AdmissionResponse {
+                uid: req.uid.clone(),
+                allowed: true,
+                ..Default::default()
+            })
+        }
+    };
+    let old: Option<GratefulSet> = match &req.old_object {
+        Some(obj) => Some(serde_json::from_value(obj.clone())?),
+        None => None,
+    };
+
+    let v = validate(&gs, old.as_ref());
+    Ok(AdmissionResponse {
+        uid: req.uid.clone(),
+        allowed: v.errors.is_empty(),
+        status: if v.errors.is_empty() {
+            None
+        } else {
+            Some(AdmissionStatus {
+                code: 422,
+                message: v.errors.join("; "),
+            })
+        },
+        warnings: v.warnings,
+    })
+}
+
+async fn handle(req: Request<Body>) -> std::result::Result<Response<Body>, hyper::Error> {
+    let admit = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/validate") => validate_request,
+        (_, "/healthz") => return Ok(Response::new(Body::from("ok"))),
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("valid response"))
+        }
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let review: AdmissionReview = match serde_json::from_slice(&body) {
+        Ok(review) => review,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("invalid AdmissionReview: {}", e)))
+                .expect("valid response"))
+        }
+    };
+    let resp = serde_json::to_vec(&review.respond(admit)).expect("serializable review");
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(resp))
+        .expect("valid response"))
+}
+
+/// Serves the admission webhooks over HTTPS on `addr`, using the PEM encoded
+/// certificate chain & PKCS#8 private key.
+pub async fn serve(addr: SocketAddr, cert_pem: &[u8], key_pem: &[u8]) -> Result<()> {
+    let identity = native_tls::Identity::from_pkcs8(cert_pem, key_pem)
+        .chain_err(|| "loading webhook certificate")?;
+    let acceptor = tokio_tls::TlsAcceptor::from(
+        native_tls::TlsAcceptor::new(identity).chain_err(|| "configuring tls")?,
+    );
+
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("serving admission webhooks on {}", addr);
+    // Each connection is handshaken & served on its own task, so a slow client doesn't hold
+    // up the others. Failed connections & handshakes are logged and dropped.
+    loop {
+        let tcp = match listener.accept().await {
+            Ok((tcp, _)) => tcp,
+            Err(e) => {
+                warn!("webhook accept failed: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let tls = match acceptor.accept(tcp).await {
+                Ok(tls) => tls,
+                Err(e) => return warn!("webhook tls handshake failed: {}", e),
+            };
+            if let Err(e) = Http::new().serve_connection(tls, service_fn(handle)).await {
+                warn!("webhook connection failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::gs::RolloutStrategy;
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+    use serde_json::json;
+
+    #[test]
+    fn dns1123_labels() {
+        let cases = vec![
+            ("ingester", true),
+            ("ingester-0", true),
+            ("0", true),
+            ("", false),
+            ("Ingester", false),
+            ("ingester_0", false),
+            ("-ingester", false),
+            ("ingester-", false),
+            ("ingester.loki", false),
+        ];
+        for (s, valid) in cases {
+            assert_eq!(is_dns1123_label(s), valid, "{:?}", s);
+        }
+        assert!(is_dns1123_label(&"a".repeat(63)));
+        assert!(!is_dns1123_label(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn selectors() {
+        let selector = |x| serde_json::from_value::<LabelSelector>(x).unwrap();
+        let labels: BTreeMap<String, String> = vec![("app", "loki"), ("tier", "write")]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        let cases = vec![
+            (json!({}), false),
+            (json!({ "matchLabels": { "app": "loki" } }), true),
+            (
+                json!({ "matchLabels": { "app": "loki", "tier": "read" } }),
+                false,
+            ),
+            (json!({ "matchLabels": { "zone": "a" } }), false),
+            (
+                json!({ "matchExpressions": [{ "key": "tier", "operator": "In", "values": ["read", "write"] }] }),
+                true,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "zone", "operator": "In", "values": ["a"] }] }),
+                false,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "tier", "operator": "NotIn", "values": ["write"] }] }),
+                false,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "zone", "operator": "NotIn", "values": ["a"] }] }),
+                true,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "app", "operator": "Exists" }] }),
+                true,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "app", "operator": "DoesNotExist" }] }),
+                false,
+            ),
+            (
+                json!({ "matchExpressions": [{ "key": "app", "operator": "Matches" }] }),
+                false,
+            ),
+            (
+                json!({
+                    "matchLabels": { "app": "loki" },
+                    "matchExpressions": [{ "key": "zone", "operator": "DoesNotExist" }],
+                }),
+                true,
+            ),
+        ];
+        for (x, matches) in cases {
+            assert_eq!(
+                selector_matches(&selector(x.clone()), &labels),
+                matches,
+                "{}",
+                x
+            );
+        }
+    }
+
+    // Sets the storage requested by the fixture's `data` claim.
+    fn storage(gs: &mut GratefulSet, size: &str) {
+        let claim = &mut gs.spec.sts_spec.volume_claim_templates.as_mut().unwrap()[0];
+        let resources = claim.spec.as_mut().unwrap().resources.as_mut().unwrap();
+        resources
+            .requests
+            .as_mut()
+            .unwrap()
+            .insert(String::from("storage"), Quantity(String::from(size)));
+    }
+
+    #[test]
+    fn validates_gratefulsets() {
+        // name, edit, whether it's an update of the unedited fixture, expected error & warning.
+        type Case = (
+            &'static str,
+            Box<dyn Fn(&mut GratefulSet)>,
+            bool,
+            Option<&'static str>,
+            Option<&'static str>,
+        );
+        let cases: Vec<Case> = vec![
+            ("valid", Box::new(|_| {}), false, None, None),
+            ("unchanged", Box::new(|_| {}), true, None, None),
+            (
+                "invalid name",
+                Box::new(|gs| gs.spec.name = String::from("Ingester")),
+                false,
+                Some("must be a DNS-1123 label"),
+                None,
+            ),
+            (
+                "long name",
+                Box::new(|gs| gs.spec.name = "i".repeat(50)),
+                false,
+                Some("is too long"),
+                None,
+            ),
+            (
+                "negative replicas",
+                Box::new(|gs| gs.spec.sts_spec.replicas = Some(-1)),
+                false,
+                Some("must not be negative"),
+                None,
+            ),
+            (
+                "unselected template",
+                Box::new(|gs| gs.spec.sts_spec.template.metadata = None),
+                false,
+                Some("selector does not match"),
+                None,
+            ),
+            (
+                "unparseable immutable field",
+                Box::new(|gs| gs.spec.immutable_fields = vec![String::from("template[")]),
+                false,
+                Some("spec.immutableFields"),
+                None,
+            ),
+            (
+                "unset immutable field",
+                Box::new(|gs| {
+                    gs.spec.immutable_fields =
+                        vec![String::from("template.spec.containers[1].image")]
+                }),
+                false,
+                None,
+                None,
+            ),
+            (
+                "immutable fields",
+                Box::new(|gs| {
+                    let md = gs.spec.sts_spec.template.metadata.as_mut().unwrap();
+                    md.annotations = Some(
+                        vec![(String::from("prometheus.io/scrape"), String::from("true"))]
+                            .into_iter()
+                            .collect(),
+                    );
+                    gs.spec.immutable_fields = vec![
+                        String::from("template.spec.containers[0].image"),
+                        String::from("template.metadata.annotations[\"prometheus.io/scrape\"]"),
+                        // defaulted
+                        String::from("template.spec.containers[0].imagePullPolicy"),
+                    ];
+                }),
+                false,
+                None,
+                None,
+            ),
+            (
+                "invalid rollout strategy",
+                Box::new(|gs| {
+                    gs.spec.rollout_strategy = Some(RolloutStrategy {
+                        max_surge: Some(IntOrString::String(String::from("lots"))),
+                        ..Default::default()
+                    })
+                }),
+                false,
+                Some("spec.rolloutStrategy"),
+                None,
+            ),
+            (
+                "abort without a previous pool",
+                Box::new(|gs| gs.spec.abort = true),
+                false,
+                None,
+                Some("no previous pool"),
+            ),
+            (
+                "migration",
+                Box::new(|gs| gs.spec.sts_spec.service_name = String::from("ingester-headless")),
+                true,
+                None,
+                Some("will migrate all 3 replicas"),
+            ),
+            (
+                "recreate",
+                Box::new(|gs| {
+                    gs.spec.migration_strategy = Some(MigrationStrategy::Recreate);
+                    gs.spec.sts_spec.pod_management_policy = Some(String::from("Parallel"));
+                }),
+                true,
+                None,
+                Some("will recreate the statefulset"),
+            ),
+            (
+                "recreate with a new serviceName",
+                Box::new(|gs| {
+                    gs.spec.migration_strategy = Some(MigrationStrategy::Recreate);
+                    gs.spec.sts_spec.service_name = String::from("ingester-headless");
+                }),
+                true,
+                Some("serviceName can't be changed"),
+                Some("will recreate the statefulset"),
+            ),
+            (
+                "storage growth",
+                Box::new(|gs| storage(gs, "20Gi")),
+                true,
+                None,
+                None,
+            ),
+            (
+                "storage shrink",
+                Box::new(|gs| {
+                    gs.spec.migration_strategy = Some(MigrationStrategy::Recreate);
+                    storage(gs, "5Gi");
+                }),
+                true,
+                None,
+                Some("shrinks the storage requested"),
+            ),
+        ];
+
+        let old = fixtures::gratefulset(|_| {});
+        for (name, edit, update, error, warning) in cases {
+            let mut gs = fixtures::gratefulset(|_| {});
+            edit(&mut gs);
+            let v = validate(&gs, if update { Some(&old) } else { None });
+            let check = |found: &[String], want: Option<&str>, what| match want {
+                Some(want) => assert!(
+                    found.iter().any(|x| x.contains(want)),
+                    "{}: expected {} {:?} in {:?}",
+                    name,
+                    what,
+                    want,
+                    found
+                ),
+                None => assert!(
+                    found.is_empty(),
+                    "{}: unexpected {}s {:?}",
+                    name,
+                    what,
+                    found
+                ),
+            };
+            check(&v.errors, error, "error");
+            check(&v.warnings, warning, "warning");
+        }
+    }
+}